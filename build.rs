@@ -1,5 +1,16 @@
 fn main() {
     println!("cargo::rerun-if-changed=build.rs");
+
+    // build scripts run on the host, so ask cargo about the target
+    if std::env::var("CARGO_CFG_TARGET_OS").unwrap() != "windows" {
+        // libfuse 2.x and libimobiledevice from the system
+        println!("cargo:rustc-link-lib=dylib=fuse");
+        println!("cargo:rustc-link-lib=dylib=imobiledevice-1.0");
+        println!("cargo:rustc-link-lib=dylib=imobiledevice-glue-1.0");
+        println!("cargo:rustc-link-lib=dylib=usbmuxd-2.0");
+        return;
+    }
+
     let root = std::env::var("CARGO_MANIFEST_DIR").unwrap();
     let lib_dir = std::path::Path::new(&root).join("lib");
     let out_dir = std::env::var("OUT_DIR").unwrap();
@@ -1,88 +1,197 @@
 #![allow(non_camel_case_types, non_upper_case_globals, dead_code)]
 #![allow(clippy::upper_case_acronyms)]
 use crate::{
-    afc_error_t, afc_error_t_AFC_E_UNKNOWN_ERROR, afc_file_mode_t, debug, idevice_connect,
-    idevice_connection_enable_ssl, idevice_connection_receive_timeout, idevice_connection_send,
-    idevice_connection_t, idevice_disconnect, idevice_error_t, idevice_error_t_IDEVICE_E_SUCCESS,
-    idevice_error_t_IDEVICE_E_TIMEOUT, idevice_private, lockdownd_service_descriptor,
+    afc_file_mode_t_AFC_FOPEN_APPEND, afc_file_mode_t_AFC_FOPEN_RDAPPEND,
+    afc_file_mode_t_AFC_FOPEN_RDONLY, afc_file_mode_t_AFC_FOPEN_RW, afc_file_mode_t_AFC_FOPEN_WR,
+    afc_file_mode_t_AFC_FOPEN_WRONLY, afc_link_type_t, afc_lock_op_t_AFC_LOCK_EX,
+    afc_lock_op_t_AFC_LOCK_SH, afc_lock_op_t_AFC_LOCK_UN, debug,
+    errno::*,
+    handles::{carries_handle, Handles},
+    idevice_connect, idevice_connection_enable_ssl, idevice_connection_t, idevice_error_t,
+    idevice_error_t_IDEVICE_E_SUCCESS, idevice_error_t_IDEVICE_E_TIMEOUT, idevice_private,
+    lockdownd_service_descriptor,
+    protocol::*,
+    transport::{IDeviceConnection, Transport},
+    SEEK_CUR, SEEK_END, SEEK_SET,
 };
-use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian};
 use num_derive::{FromPrimitive, ToPrimitive};
-use std::{collections::HashMap, ffi::CStr, mem::MaybeUninit, slice::from_raw_parts, sync::Mutex};
-
-pub const AFCMAGIC: &[u8; 8] = b"CFA6LPAA";
-
-#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive, PartialEq, PartialOrd)]
-pub enum afc_opcode_t {
-    STATUS = 0x00000001,
-    DATA = 0x00000002,                     // Data */
-    READ_DIR = 0x00000003,                 // ReadDir */
-    READ_FILE = 0x00000004,                // ReadFile */
-    WRITE_FILE = 0x00000005,               // WriteFile */
-    WRITE_PART = 0x00000006,               // WritePart */
-    TRUNCATE = 0x00000007,                 // TruncateFile */
-    REMOVE_PATH = 0x00000008,              // RemovePath */
-    MAKE_DIR = 0x00000009,                 // MakeDir */
-    GET_FILE_INFO = 0x0000000a,            // GetFileInfo */
-    GET_DEVINFO = 0x0000000b,              // GetDeviceInfo */
-    WRITE_FILE_ATOM = 0x0000000c,          // WriteFileAtomic (tmp file+rename) */
-    FILE_OPEN = 0x0000000d,                // FileRefOpen */
-    FILE_OPEN_RES = 0x0000000e,            // FileRefOpenResult */
-    READ = 0x0000000f,                     // FileRefRead */
-    WRITE = 0x00000010,                    // FileRefWrite */
-    FILE_SEEK = 0x00000011,                // FileRefSeek */
-    FILE_TELL = 0x00000012,                // FileRefTell */
-    FILE_TELL_RES = 0x00000013,            // FileRefTellResult */
-    FILE_CLOSE = 0x00000014,               // FileRefClose */
-    FILE_SET_SIZE = 0x00000015,            // FileRefSetFileSize (ftruncate) */
-    GET_CON_INFO = 0x00000016,             // GetConnectionInfo */
-    SET_CON_OPTIONS = 0x00000017,          // SetConnectionOptions */
-    RENAME_PATH = 0x00000018,              // RenamePath */
-    SET_FS_BS = 0x00000019,                // SetFSBlockSize (0x800000) */
-    SET_SOCKET_BS = 0x0000001A,            // SetSocketBlockSize (0x800000) */
-    FILE_LOCK = 0x0000001B,                // FileRefLock */
-    MAKE_LINK = 0x0000001C,                // MakeLink */
-    SET_FILE_TIME = 0x0000001E,            // set st_mtime */
-    REMOVE_PATH_AND_CONTENTS = 0x00000022, /* RemovePathAndContents */
+use num_traits::FromPrimitive as _;
+use std::{
+    collections::HashMap,
+    ffi::{c_void, CStr, CString},
+    io::SeekFrom,
+    mem::MaybeUninit,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Requests sent ahead of their replies, small enough that neither side's buffers fill up
+pub(crate) const PIPELINE_DEPTH: usize = 32;
+
+/// Largest READ the device is asked for at once
+pub(crate) const MAX_READ_SIZE: u64 = 65536;
+
+/// Pause between attempts to reach the device again
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Opens a new connection to the service the client was using, ready for AFC requests
+pub type Connector = Box<dyn Fn() -> Option<Box<dyn Transport>> + Send + Sync>;
+
+struct Reconnect {
+    connect: Connector,
+    window: Duration,
+    /// When the connection was found dead, until a request gets through again
+    down_since: Mutex<Option<Instant>>,
 }
 
-pub struct Client {
-    pub(crate) socket: Mutex<IDeviceConnection>,
-    pub(crate) packet_num: Mutex<u64>,
+/// Why a request failed, as the status the device answered with
+#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive, PartialEq, Eq)]
+pub enum AfcError {
+    UnknownError = 1,
+    OpHeaderInvalid = 2,
+    NoResources = 3,
+    ReadError = 4,
+    WriteError = 5,
+    UnknownPacketType = 6,
+    InvalidArg = 7,
+    ObjectNotFound = 8,
+    ObjectIsDir = 9,
+    PermDenied = 10,
+    ServiceNotConnected = 11,
+    OpTimeout = 12,
+    TooMuchData = 13,
+    EndOfData = 14,
+    OpNotSupported = 15,
+    ObjectExists = 16,
+    ObjectBusy = 17,
+    NoSpaceLeft = 18,
+    OpWouldBlock = 19,
+    IoError = 20,
+    OpInterrupted = 21,
+    OpInProgress = 22,
+    InternalError = 23,
+    MuxError = 30,
+    NoMem = 31,
+    NotEnoughData = 32,
+    DirNotEmpty = 33,
 }
 
-pub(crate) struct IDeviceConnection {
-    pub(crate) conn: usize,
+/// Reads a STATUS reply, 0 meaning success
+pub(crate) fn status(code: u64) -> Result<(), AfcError> {
+    match code {
+        0 => Ok(()),
+        code => Err(AfcError::from_u64(code).unwrap_or(AfcError::UnknownError)),
+    }
 }
 
-impl IDeviceConnection {
-    pub(crate) fn new(connection: idevice_connection_t) -> Self {
-        Self {
-            conn: connection as usize,
+impl AfcError {
+    /// Translates the status into the errno FUSE callbacks return negated
+    pub fn to_errno(self) -> i32 {
+        match self {
+            AfcError::ObjectNotFound => ENOENT,
+            AfcError::ObjectIsDir => EISDIR,
+            AfcError::PermDenied => EACCES,
+            AfcError::ObjectExists => EEXIST,
+            AfcError::ObjectBusy => EBUSY,
+            AfcError::NoSpaceLeft => ENOSPC,
+            AfcError::DirNotEmpty => ENOTEMPTY,
+            AfcError::InvalidArg => EINVAL,
+            AfcError::ReadError => ENOTDIR,
+            AfcError::NoResources => EMFILE,
+            AfcError::TooMuchData => EFBIG,
+            AfcError::NoMem => ENOMEM,
+            AfcError::OpNotSupported => ENOSYS,
+            AfcError::OpWouldBlock => EAGAIN,
+            AfcError::OpInterrupted => EINTR,
+            AfcError::ServiceNotConnected | AfcError::MuxError => ENXIO,
+            _ => EIO,
         }
     }
+}
 
-    pub(crate) fn none() -> Self {
-        Self { conn: 0 }
+impl std::fmt::Display for AfcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} ({})", self, *self as i32)
     }
+}
 
-    pub(crate) fn connection(&self) -> idevice_connection_t {
-        self.conn as idevice_connection_t
+impl std::error::Error for AfcError {}
+
+/// How `Client::open_file` opens a file, after the fopen(3) mode each stands for
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OpenMode {
+    /// `r`, reading an existing file
+    ReadOnly = afc_file_mode_t_AFC_FOPEN_RDONLY as isize,
+    /// `r+`, reading and writing an existing file
+    ReadWrite = afc_file_mode_t_AFC_FOPEN_RW as isize,
+    /// `w`, writing the file created or cut to nothing
+    Truncate = afc_file_mode_t_AFC_FOPEN_WRONLY as isize,
+    /// `w+`, as `Truncate` but reading too
+    ReadTruncate = afc_file_mode_t_AFC_FOPEN_WR as isize,
+    /// `a`, writing at the end of the file, created if missing
+    Append = afc_file_mode_t_AFC_FOPEN_APPEND as isize,
+    /// `a+`, as `Append` but reading too
+    ReadAppend = afc_file_mode_t_AFC_FOPEN_RDAPPEND as isize,
+}
+
+/// What `Client::file_lock` does with the lock of an open file, as flock(2) would
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LockOp {
+    Shared = afc_lock_op_t_AFC_LOCK_SH as isize,
+    Exclusive = afc_lock_op_t_AFC_LOCK_EX as isize,
+    Unlock = afc_lock_op_t_AFC_LOCK_UN as isize,
+}
+
+pub struct Client {
+    pub(crate) socket: Mutex<Box<dyn Transport>>,
+    pub(crate) packet_num: Mutex<u64>,
+    handles: Mutex<Handles>,
+    reconnect: Option<Reconnect>,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::with_transport(IDeviceConnection::none())
     }
 }
 
 impl Client {
-    pub fn default() -> Self {
+    pub fn with_transport<T: Transport + 'static>(transport: T) -> Self {
         Self {
-            socket: Mutex::new(IDeviceConnection::none()),
+            socket: Mutex::new(Box::new(transport)),
             packet_num: Mutex::new(0),
+            handles: Mutex::new(Handles::new()),
+            reconnect: None,
         }
     }
 
-    pub fn new(
-        device: *mut idevice_private,
-        service: *mut lockdownd_service_descriptor,
-    ) -> Option<Self> {
+    /// Lets the client replace a dead connection through `connect`, reopening its
+    /// files. Requests fail with `IoError` once the device has been unreachable for
+    /// `window`.
+    pub fn reconnect_with(mut self, connect: Connector, window: Duration) -> Self {
+        self.reconnect = Some(Reconnect {
+            connect,
+            window,
+            down_since: Mutex::new(None),
+        });
+        self
+    }
+
+    /// The connection, e.g. for handing a service session set up here to another client
+    pub fn into_transport(self) -> Box<dyn Transport> {
+        self.socket.into_inner().unwrap()
+    }
+
+    /// Connects to the service `service` describes, as lockdown started it on `device`
+    ///
+    /// # Safety
+    ///
+    /// `device` must be a valid `idevice_t` and `service` a valid
+    /// `lockdownd_service_descriptor_t`, as libimobiledevice hands them out.
+    pub unsafe fn new(device: *mut c_void, service: *mut c_void) -> Option<Self> {
+        let device = device.cast::<idevice_private>();
+        let service = service.cast::<lockdownd_service_descriptor>();
         let mut device_connection = MaybeUninit::<idevice_connection_t>::zeroed();
         let device_connection_ptr = device_connection.as_mut_ptr();
         if unsafe { idevice_connect(device, (*service).port, device_connection_ptr) }
@@ -93,20 +202,22 @@ impl Client {
                 unsafe { idevice_connection_enable_ssl(connection) };
             }
 
-            Some(Self {
-                socket: Mutex::new(IDeviceConnection::new(connection)),
-                packet_num: Mutex::new(0),
-            })
+            Some(Self::with_transport(IDeviceConnection::new(connection)))
         } else {
             None
         }
     }
 
+    /// Whether another thread is using the connection right now
+    pub fn is_busy(&self) -> bool {
+        self.socket.try_lock().is_err()
+    }
+
     pub fn close(&self) -> i32 {
-        unsafe { idevice_disconnect(self.socket.lock().unwrap().connection()) }
+        self.socket.lock().unwrap().disconnect()
     }
 
-    pub fn get_file_info(&self, path: *const i8) -> AfcResponse {
+    pub(crate) fn get_file_info(&self, path: *const i8) -> AfcResponse {
         self.operate(
             afc_opcode_t::GET_FILE_INFO,
             0,
@@ -117,7 +228,7 @@ impl Client {
         )
     }
 
-    pub fn read_directory(&self, path: *const i8) -> AfcResponse {
+    pub(crate) fn read_directory(&self, path: *const i8) -> AfcResponse {
         self.operate(
             afc_opcode_t::READ_DIR,
             0,
@@ -128,11 +239,11 @@ impl Client {
         )
     }
 
-    pub fn get_device_info(&self) -> AfcResponse {
+    pub(crate) fn get_device_info(&self) -> AfcResponse {
         self.operate(afc_opcode_t::GET_DEVINFO, 0, Vec::new())
     }
 
-    pub fn file_open(&self, path: *const i8, mode: u64) -> AfcResponse {
+    pub(crate) fn file_open(&self, path: *const i8, mode: u64) -> AfcResponse {
         self.operate(
             afc_opcode_t::FILE_OPEN,
             0,
@@ -144,49 +255,114 @@ impl Client {
         )
     }
 
-    pub fn file_close(&self, handle: u64) -> AfcResponse {
+    /// Closes an open file; its handle is not valid afterwards
+    pub fn file_close(&self, handle: u64) -> Result<(), AfcError> {
         self.operate(
             afc_opcode_t::FILE_CLOSE,
             0,
             afc_fclose_t { handle }.to_bytes(),
         )
+        .into_result()
+        .map(drop)
+    }
+
+    /// Moves the file pointer of an open file
+    pub fn file_seek(&self, handle: u64, pos: SeekFrom) -> Result<(), AfcError> {
+        let request = seek_request(handle, pos)?;
+        self.pipeline(vec![request])
+            .pop()
+            .unwrap()
+            .into_result()
+            .map(drop)
+    }
+
+    /// Where the file pointer of an open file is
+    pub fn file_tell(&self, handle: u64) -> Result<u64, AfcError> {
+        self.operate(
+            afc_opcode_t::FILE_TELL,
+            0,
+            afc_ftell_t { handle }.to_bytes(),
+        )
+        .into_num()
+    }
+
+    /// Changes the size of an open file, like ftruncate(2)
+    pub fn file_set_size(&self, handle: u64, size: u64) -> Result<(), AfcError> {
+        self.operate(
+            afc_opcode_t::FILE_SET_SIZE,
+            0,
+            afc_fset_size_t {
+                handle,
+                newsize: size,
+            }
+            .to_bytes(),
+        )
+        .into_result()
+        .map(drop)
     }
 
-    pub fn file_seek(&self, handle: u64, offset: i64, whence: u64) -> AfcResponse {
+    /// Takes or releases the device-wide lock of an open file. The device never
+    /// waits for a lock held elsewhere and answers `OpWouldBlock` instead
+    pub fn file_lock(&self, handle: u64, operation: LockOp) -> Result<(), AfcError> {
         self.operate(
-            afc_opcode_t::FILE_SEEK,
+            afc_opcode_t::FILE_LOCK,
             0,
-            afc_seek_t {
+            afc_lock_t {
                 handle,
-                offset,
-                whence,
+                op: operation as u64,
             }
             .to_bytes(),
         )
+        .into_result()
+        .map(drop)
+    }
+
+    /// Lock an open file holds on the device. A lock the client could not take back
+    /// after reconnecting is not held anymore
+    pub fn held_lock(&self, handle: u64) -> Option<LockOp> {
+        let lock = self.handles.lock().unwrap().held_lock(handle)?;
+        [LockOp::Shared, LockOp::Exclusive]
+            .into_iter()
+            .find(|operation| *operation as u64 == lock)
+    }
+
+    /// Size of an open file, found by seeking to its end; the file pointer is left there
+    pub fn file_size(&self, handle: u64) -> Result<u64, AfcError> {
+        let mut responses = self.sequence(vec![
+            seek_request(handle, SeekFrom::End(0))?,
+            AfcRequest::new(afc_opcode_t::FILE_TELL, afc_ftell_t { handle }.to_bytes()),
+        ]);
+        let tell = responses.pop().unwrap();
+        responses.pop().unwrap().into_result()?;
+        tell.into_num()
     }
 
-    pub fn file_read(&self, handle: u64, size: u64) -> AfcResponse {
+    /// Reads up to `size` bytes from the file pointer, fewer at the end of the file
+    pub fn file_read(&self, handle: u64, size: u64) -> Result<Vec<u8>, AfcError> {
         self.operate(
             afc_opcode_t::READ,
             0,
             afc_fread_t { handle, size }.to_bytes(),
         )
+        .into_bytes()
     }
 
-    pub fn file_write(&self, handle: u64, data: *const i8, size: u32) -> AfcResponse {
-        let buf: &[u8] = unsafe { from_raw_parts(data as *const u8, size as _) };
+    /// Writes all of `data` at the file pointer
+    pub fn file_write(&self, handle: u64, data: &[u8]) -> Result<(), AfcError> {
         self.operate(
             afc_opcode_t::WRITE,
             size_of::<u64>() as u64,
             afc_fwrite_t {
                 handle,
-                data: buf.to_vec(),
+                data: data.to_vec(),
             }
             .to_bytes(),
         )
+        .into_result()
+        .map(drop)
     }
 
-    pub fn truncate(&self, path: *const i8, size: u64) -> AfcResponse {
+    pub(crate) fn truncate(&self, path: *const i8, size: u64) -> AfcResponse {
         self.operate(
             afc_opcode_t::TRUNCATE,
             0,
@@ -198,7 +374,7 @@ impl Client {
         )
     }
 
-    pub fn remove_path(&self, path: *const i8) -> AfcResponse {
+    pub(crate) fn remove_path(&self, path: *const i8) -> AfcResponse {
         self.operate(
             afc_opcode_t::REMOVE_PATH,
             0,
@@ -209,7 +385,18 @@ impl Client {
         )
     }
 
-    pub fn make_directory(&self, path: *const i8) -> AfcResponse {
+    pub(crate) fn remove_path_and_contents(&self, path: *const i8) -> AfcResponse {
+        self.operate(
+            afc_opcode_t::REMOVE_PATH_AND_CONTENTS,
+            0,
+            afc_rm_t {
+                filename: unsafe { CStr::from_ptr(path).to_bytes_with_nul().to_vec() },
+            }
+            .to_bytes(),
+        )
+    }
+
+    pub(crate) fn make_directory(&self, path: *const i8) -> AfcResponse {
         self.operate(
             afc_opcode_t::MAKE_DIR,
             0,
@@ -220,78 +407,483 @@ impl Client {
         )
     }
 
-    pub fn operate(&self, operation: afc_opcode_t, data_len: u64, payload: Vec<u8>) -> AfcResponse {
-        let socket = self.socket.lock().unwrap();
+    pub(crate) fn rename_path(&self, from: *const i8, to: *const i8) -> AfcResponse {
+        self.operate(
+            afc_opcode_t::RENAME_PATH,
+            0,
+            afc_rename_t {
+                from: unsafe { CStr::from_ptr(from).to_bytes_with_nul().to_vec() },
+                to: unsafe { CStr::from_ptr(to).to_bytes_with_nul().to_vec() },
+            }
+            .to_bytes(),
+        )
+    }
+
+    pub(crate) fn set_file_time(&self, path: *const i8, mtime: u64) -> AfcResponse {
+        self.operate(
+            afc_opcode_t::SET_FILE_TIME,
+            0,
+            afc_set_file_time_t {
+                mtime,
+                filename: unsafe { CStr::from_ptr(path).to_bytes_with_nul().to_vec() },
+            }
+            .to_bytes(),
+        )
+    }
+
+    pub(crate) fn make_link(
+        &self,
+        link_type: afc_link_type_t,
+        target: *const i8,
+        link_name: *const i8,
+    ) -> AfcResponse {
+        self.operate(
+            afc_opcode_t::MAKE_LINK,
+            0,
+            afc_link_t {
+                link_type: link_type as _,
+                target: unsafe { CStr::from_ptr(target).to_bytes_with_nul().to_vec() },
+                link_name: unsafe { CStr::from_ptr(link_name).to_bytes_with_nul().to_vec() },
+            }
+            .to_bytes(),
+        )
+    }
+
+    /// Sends a GET_FILE_INFO for each path through the dispatcher
+    pub(crate) fn get_file_info_many(&self, paths: &[CString]) -> Vec<AfcResponse> {
+        self.pipeline(
+            paths
+                .iter()
+                .map(|path| {
+                    AfcRequest::new(
+                        afc_opcode_t::GET_FILE_INFO,
+                        afc_stat_t {
+                            filename: path.as_bytes_with_nul().to_vec(),
+                        }
+                        .to_bytes(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// Reads up to `size` bytes at `offset` as one seek and `MAX_READ_SIZE` reads sent together
+    pub fn file_read_at(&self, handle: u64, offset: u64, size: u64) -> Result<Vec<u8>, AfcError> {
+        self.read_chunks(handle, Some(offset), size)
+    }
+
+    /// Reads up to `size` bytes from the current position as `MAX_READ_SIZE` reads sent together
+    pub fn file_read_bulk(&self, handle: u64, size: u64) -> Result<Vec<u8>, AfcError> {
+        self.read_chunks(handle, None, size)
+    }
+
+    fn read_chunks(
+        &self,
+        handle: u64,
+        offset: Option<u64>,
+        size: u64,
+    ) -> Result<Vec<u8>, AfcError> {
+        let mut requests = Vec::new();
+        if let Some(offset) = offset {
+            requests.push(seek_request(handle, SeekFrom::Start(offset))?);
+        }
+        let mut sizes = Vec::new();
+        let mut remaining = size;
+        while remaining > 0 {
+            let chunk = remaining.min(MAX_READ_SIZE);
+            requests.push(AfcRequest::new(
+                afc_opcode_t::READ,
+                afc_fread_t {
+                    handle,
+                    size: chunk,
+                }
+                .to_bytes(),
+            ));
+            sizes.push(chunk);
+            remaining -= chunk;
+        }
+
+        let mut responses = self.pipeline(requests).into_iter();
+        if offset.is_some() {
+            responses.next().unwrap().into_result()?;
+        }
+
+        let mut data = Vec::new();
+        for (response, chunk) in responses.zip(sizes) {
+            let bytes = match response.into_bytes() {
+                Ok(bytes) => bytes,
+                Err(status) if data.is_empty() => return Err(status),
+                Err(_) => break,
+            };
+            let short = (bytes.len() as u64) < chunk;
+            data.extend(bytes);
+            // the reads after a short one are at end of file
+            if short {
+                break;
+            }
+        }
+        Ok(data)
+    }
+
+    /// Writes `data` at `offset`, with no other request on the handle in between
+    pub fn file_write_at(&self, handle: u64, offset: u64, data: &[u8]) -> Result<(), AfcError> {
+        let mut responses = self.sequence(vec![
+            seek_request(handle, SeekFrom::Start(offset))?,
+            AfcRequest {
+                operation: afc_opcode_t::WRITE,
+                data_len: size_of::<u64>() as u64,
+                payload: afc_fwrite_t {
+                    handle,
+                    data: data.to_vec(),
+                }
+                .to_bytes(),
+            },
+        ]);
+        let write = responses.pop().unwrap();
+        responses.pop().unwrap().into_result()?;
+        write.into_result().map(drop)
+    }
+
+    pub(crate) fn operate(
+        &self,
+        operation: afc_opcode_t,
+        data_len: u64,
+        payload: Vec<u8>,
+    ) -> AfcResponse {
+        self.pipeline(vec![AfcRequest {
+            operation,
+            data_len,
+            payload,
+        }])
+        .pop()
+        .unwrap()
+    }
+
+    /// Sends `requests` keeping up to `PIPELINE_DEPTH` of them in flight
+    pub(crate) fn pipeline(&self, requests: Vec<AfcRequest>) -> Vec<AfcResponse> {
+        self.dispatch(requests, PIPELINE_DEPTH, false)
+    }
+
+    /// Runs `requests` back to back with no other request in between, stopping at the
+    /// first failure; the requests left unsent get its status
+    pub(crate) fn sequence(&self, requests: Vec<AfcRequest>) -> Vec<AfcResponse> {
+        self.dispatch(requests, 1, true)
+    }
+
+    /// Request dispatcher: keeps up to `depth` requests in flight and matches the replies
+    /// to them by packet number, returning one response per request in order.
+    ///
+    /// When the connection dies and the client can reconnect, the requests not sent yet
+    /// are sent on the new connection. Those that were sent may have been carried out
+    /// already, so only the ones that can safely run twice are sent again; the others
+    /// fail with `AfcError::IoError`.
+    fn dispatch(
+        &self,
+        requests: Vec<AfcRequest>,
+        depth: usize,
+        stop_on_error: bool,
+    ) -> Vec<AfcResponse> {
+        let mut socket = self.socket.lock().unwrap();
         let mut num = self.packet_num.lock().unwrap();
-        *num += 1;
+        let mut handles = self.handles.lock().unwrap();
+
+        let mut responses: Vec<Option<AfcResponse>> = vec![None; requests.len()];
+        let failure = loop {
+            let exchange = self.exchange(
+                socket.as_mut(),
+                &mut num,
+                Some(&mut handles),
+                &requests,
+                &mut responses,
+                depth,
+                stop_on_error,
+            );
+            let Some(unanswered) = exchange.lost else {
+                if let Some(reconnect) = &self.reconnect {
+                    *reconnect.down_since.lock().unwrap() = None;
+                }
+                break exchange.failure;
+            };
+            if self.reconnect.is_none() {
+                for index in unanswered {
+                    responses[index] = Some(AfcResponse::error());
+                }
+                break exchange.failure;
+            }
+            let mut gave_up = false;
+            for index in unanswered {
+                if !replayable(requests[index].operation) {
+                    let mut response = AfcResponse::error_with(AfcError::IoError);
+                    handles.track(&requests[index], &mut response);
+                    responses[index] = Some(response);
+                    gave_up = true;
+                }
+            }
+            if !self.recover(&mut socket, &mut num, &mut handles) {
+                break Some(AfcError::IoError);
+            }
+            if gave_up && stop_on_error {
+                break Some(AfcError::IoError);
+            }
+        };
+
+        // requests never sent fail like the one that stopped the others
+        responses
+            .into_iter()
+            .map(|response| {
+                response.unwrap_or_else(|| {
+                    AfcResponse::error_with(failure.unwrap_or(AfcError::MuxError))
+                })
+            })
+            .collect()
+    }
+
+    /// Sends the requests that have no response yet and collects their replies.
+    ///
+    /// With `handles`, requests on files are addressed with the device's handles and
+    /// their effect is followed.
+    #[allow(clippy::too_many_arguments)]
+    fn exchange(
+        &self,
+        socket: &mut dyn Transport,
+        num: &mut u64,
+        mut handles: Option<&mut Handles>,
+        requests: &[AfcRequest],
+        responses: &mut [Option<AfcResponse>],
+        depth: usize,
+        stop_on_error: bool,
+    ) -> Exchange {
+        let mut in_flight: HashMap<u64, usize> = HashMap::new();
+        let unanswered: Vec<usize> = (0..requests.len())
+            .filter(|&index| responses[index].is_none())
+            .collect();
+        let mut pending = unanswered.into_iter();
+        let mut failure = None;
+        let mut lost = false;
+
+        loop {
+            while failure.is_none() && in_flight.len() < depth {
+                let Some(index) = pending.next() else {
+                    break;
+                };
+                let request = &requests[index];
+                let handle = match &handles {
+                    Some(handles) if carries_handle(request.operation) => {
+                        Some(handles.device_handle(request))
+                    }
+                    _ => None,
+                };
+                *num += 1;
+                if self.send_request(socket, *num, request, handle) {
+                    in_flight.insert(*num, index);
+                } else {
+                    failure = Some(AfcError::MuxError);
+                    lost = true;
+                }
+            }
+            if in_flight.is_empty() {
+                break;
+            }
+
+            let Some((header, data)) = self.receive_packet(socket) else {
+                lost = true;
+                break;
+            };
+            let packet_num = header.packet_num;
+            let Some(index) = in_flight.remove(&packet_num) else {
+                /* a reply to nothing we sent means the stream is out of sync */
+                eprintln!("Invalid response header");
+                lost = true;
+                break;
+            };
+            let request = &requests[index];
+            let mut response = match data {
+                Some(data) => {
+                    let afc_data = parse_afc(request.operation, header.operation, &data);
+                    AfcResponse {
+                        status: afc_data.0,
+                        header,
+                        data: afc_data.1,
+                    }
+                }
+                None => AfcResponse::error(),
+            };
+            if let Some(handles) = handles.as_deref_mut() {
+                handles.track(request, &mut response);
+            }
+            if let (true, Err(status)) = (stop_on_error, response.status) {
+                failure = Some(status);
+            }
+            responses[index] = Some(response);
+        }
+
+        Exchange {
+            failure,
+            lost: lost.then(|| in_flight.into_values().collect()),
+        }
+    }
+
+    /// Replaces the dead connection and reopens the files, retrying until the
+    /// reconnect window has passed
+    fn recover(
+        &self,
+        socket: &mut Box<dyn Transport>,
+        num: &mut u64,
+        handles: &mut Handles,
+    ) -> bool {
+        let Some(reconnect) = &self.reconnect else {
+            return false;
+        };
+        let since = *reconnect
+            .down_since
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Instant::now);
+        socket.disconnect();
+
+        loop {
+            debug!("Reconnecting...");
+            if let Some(transport) = (reconnect.connect)() {
+                *socket = transport;
+                *num = 0;
+                if self.reopen(socket.as_mut(), num, handles) {
+                    debug!("Reconnected");
+                    return true;
+                }
+                socket.disconnect();
+            }
+
+            let elapsed = since.elapsed();
+            if elapsed >= reconnect.window {
+                debug!("Cannot reconnect to the device");
+                return false;
+            }
+            std::thread::sleep(RECONNECT_INTERVAL.min(reconnect.window - elapsed));
+        }
+    }
+
+    /// Opens the files again on a new connection and puts their pointers and locks
+    /// back; false if the connection was lost meanwhile
+    fn reopen(&self, socket: &mut dyn Transport, num: &mut u64, handles: &mut Handles) -> bool {
+        let (files, requests): (Vec<u64>, Vec<AfcRequest>) = handles.reopen().into_iter().unzip();
+        let mut responses = vec![None; requests.len()];
+        let exchange = self.exchange(
+            socket,
+            num,
+            None,
+            &requests,
+            &mut responses,
+            PIPELINE_DEPTH,
+            false,
+        );
+        if exchange.lost.is_some() {
+            return false;
+        }
+        for (handle, response) in files.into_iter().zip(responses) {
+            let device = response
+                .filter(|response| response.status.is_ok())
+                .and_then(extract_num);
+            if device.is_none() {
+                debug!("Cannot reopen file {} after reconnecting", handle);
+            }
+            handles.rebind(handle, device);
+        }
 
+        let (files, requests): (Vec<u64>, Vec<AfcRequest>) = handles.restore().into_iter().unzip();
+        let mut responses = vec![None; requests.len()];
+        let exchange = self.exchange(
+            socket,
+            num,
+            None,
+            &requests,
+            &mut responses,
+            PIPELINE_DEPTH,
+            false,
+        );
+        if exchange.lost.is_some() {
+            return false;
+        }
+        for ((handle, request), response) in files.into_iter().zip(&requests).zip(responses) {
+            if response.is_none_or(|response| response.status.is_err()) {
+                handles.lost(handle, request.operation);
+            }
+        }
+        true
+    }
+
+    /// Writes header and payload of one request, with `handle` in place of the one the
+    /// payload starts with, returning false if the transport failed
+    fn send_request(
+        &self,
+        connection: &mut dyn Transport,
+        packet_num: u64,
+        request: &AfcRequest,
+        handle: Option<u64>,
+    ) -> bool {
+        let operation = request.operation;
+        let payload = &request.payload[..];
         let payload_len = payload.len() as u64;
-        let data_len = if data_len > 0 { data_len } else { payload_len };
+        let data_len = if request.data_len > 0 {
+            request.data_len
+        } else {
+            payload_len
+        };
         let afc_header_size = size_of::<AfcHeader>() as u64;
         let request_header = AfcHeader {
             magic: *AFCMAGIC,
             entire_length: afc_header_size + payload_len,
             this_length: afc_header_size + data_len,
-            packet_num: *num,
+            packet_num,
             operation: operation as u64,
         };
 
-        let (res, _) = self.send_packet(&socket, request_header.to_bytes(), afc_header_size as u32);
+        let mut head = request_header.to_bytes();
+        let payload = match handle {
+            Some(handle) if payload.len() >= 8 => {
+                head.extend_from_slice(&handle.to_le_bytes());
+                &payload[8..]
+            }
+            _ => payload,
+        };
+        let (res, _) = connection.send(&head);
         if res != idevice_error_t_IDEVICE_E_SUCCESS {
-            return AfcResponse::error_with(res);
+            debug!("Failed to send packet: {:?}", res);
+            return false;
         }
 
-        let (res, _) = self.send_packet(&socket, payload, payload_len as _);
+        let (res, _) = connection.send(payload);
         if res != idevice_error_t_IDEVICE_E_SUCCESS {
-            return AfcResponse::error_with(res);
+            debug!("Failed to send packet: {:?}", res);
+            return false;
         }
-
-        self.receive_packet(&socket, operation, *num)
+        true
     }
 
     pub(crate) fn send_packet(
         &self,
-        connection: &std::sync::MutexGuard<IDeviceConnection>,
+        connection: &mut dyn Transport,
         data: Vec<u8>,
         len: u32,
     ) -> (idevice_error_t, u32) {
-        let mut sent_bytes = 0;
-        let res = unsafe {
-            idevice_connection_send(
-                connection.connection(),
-                data.as_ptr() as *const i8,
-                len,
-                &mut sent_bytes,
-            )
-        };
-        (res, sent_bytes)
+        connection.send(&data[..len as usize])
     }
 
+    /// Reads the next reply, whichever request it answers.
+    ///
+    /// Returns `None` when the stream can no longer be trusted, and no data for a
+    /// well-framed reply that carries nothing usable.
     fn receive_packet(
         &self,
-        connection: &std::sync::MutexGuard<IDeviceConnection>,
-        operation: afc_opcode_t,
-        packet_num: u64,
-    ) -> AfcResponse {
+        connection: &mut dyn Transport,
+    ) -> Option<(AfcHeader, Option<Vec<u8>>)> {
         let mut header = vec![0u8; std::mem::size_of::<AfcHeader>() as _];
 
-        let mut recv_bytes = 0;
-        let response_header = match unsafe {
-            idevice_connection_receive_timeout(
-                connection.connection(),
-                header.as_mut_ptr() as *mut i8,
-                header.len() as u32,
-                &mut recv_bytes,
-                5000,
-            )
-        } {
+        let response_header = match connection.receive_timeout(&mut header, 5000).0 {
             idevice_error_t_IDEVICE_E_SUCCESS => {
                 if let Some(response_header) = parse_header(&header) {
                     let response_magic = response_header.magic;
-                    let response_packet_pnum = response_header.packet_num;
                     /* check if it's a valid AFC header */
-                    /* check if it has the correct packet number */
-                    if AFCMAGIC != &response_magic || packet_num != response_packet_pnum {
+                    if AFCMAGIC != &response_magic {
                         eprintln!("Invalid response header");
                         None
                     } else {
@@ -309,267 +901,171 @@ impl Client {
                 eprintln!("TCP error: {:?}", val);
                 None
             }
-        };
+        }?;
 
         /* then, read the attached packet */
-        if let Some(response_header) = response_header {
-            if response_header.this_length < std::mem::size_of::<AfcHeader>() as u64 {
-                debug!("Invalid AFCPacket header received!");
-                return AfcResponse::error();
-            }
-
-            if response_header.this_length == response_header.entire_length
-                && response_header.entire_length == std::mem::size_of::<AfcHeader>() as u64
-            {
-                debug!("Empty AFCPacket received!");
-                return AfcResponse::error();
-            }
+        if response_header.this_length < std::mem::size_of::<AfcHeader>() as u64 {
+            debug!("Invalid AFCPacket header received!");
+            return None;
+        }
 
-            let entire_len =
-                response_header.entire_length - std::mem::size_of::<AfcHeader>() as u64;
-            let this_len = response_header.this_length - std::mem::size_of::<AfcHeader>() as u64;
+        // an empty DATA packet is a valid reply, e.g. reading at end of file
+        if response_header.this_length == response_header.entire_length
+            && response_header.entire_length == std::mem::size_of::<AfcHeader>() as u64
+            && response_header.operation != afc_opcode_t::DATA as u64
+        {
+            debug!("Empty AFCPacket received!");
+            return Some((response_header, None));
+        }
 
-            let status_only = response_header.operation == 1;
+        let entire_len = response_header.entire_length - std::mem::size_of::<AfcHeader>() as u64;
+        let this_len = response_header.this_length - std::mem::size_of::<AfcHeader>() as u64;
 
-            let mut recv_bytes = 0;
-            let mut response = Vec::new();
+        let mut response = Vec::new();
 
-            if this_len > 0 {
-                let mut buf = vec![0u8; this_len as _];
-                unsafe {
-                    idevice_connection_receive_timeout(
-                        connection.connection(),
-                        buf.as_mut_ptr() as *mut i8,
-                        this_len as u32,
-                        &mut recv_bytes,
-                        5000,
-                    )
-                };
+        if this_len > 0 {
+            let mut buf = vec![0u8; this_len as _];
+            let (_, recv_bytes) = connection.receive_timeout(&mut buf, 5000);
 
-                if recv_bytes == 0 {
-                    debug!("Did not get packet contents!");
-                    return AfcResponse::error();
-                }
+            if recv_bytes == 0 {
+                debug!("Did not get packet contents!");
+                return None;
+            }
 
-                if recv_bytes < this_len as _ {
-                    debug!("Could not receive this_len={:?} bytes", this_len);
-                    return AfcResponse::error();
-                }
-                response.extend_from_slice(&buf[0..recv_bytes as usize]);
+            if recv_bytes < this_len as _ {
+                debug!("Could not receive this_len={:?} bytes", this_len);
+                return None;
             }
+            response.extend_from_slice(&buf[0..recv_bytes as usize]);
+        }
 
-            let mut current_count = this_len;
-
-            if entire_len > this_len {
-                while current_count < entire_len {
-                    let mut buf = vec![0u8; (entire_len - current_count) as _];
-                    unsafe {
-                        idevice_connection_receive_timeout(
-                            connection.connection(),
-                            buf.as_mut_ptr() as *mut i8,
-                            (entire_len - current_count) as u32,
-                            &mut recv_bytes,
-                            5000,
-                        )
-                    };
-                    if recv_bytes == 0 {
-                        debug!("Error receiving data (recv returned {:?})", recv_bytes);
-                        break;
-                    }
+        let mut current_count = this_len;
 
-                    response.extend_from_slice(&buf[0..recv_bytes as usize]);
-                    current_count += recv_bytes as u64;
+        if entire_len > this_len {
+            while current_count < entire_len {
+                let mut buf = vec![0u8; (entire_len - current_count) as _];
+                let (_, recv_bytes) = connection.receive_timeout(&mut buf, 5000);
+                if recv_bytes == 0 {
+                    debug!("Error receiving data (recv returned {:?})", recv_bytes);
+                    break;
                 }
 
-                if current_count < entire_len {
-                    debug!(
-                        "WARNING: could not receive full packet (read {:?}, size {:?})",
-                        current_count, entire_len
-                    );
-                }
+                response.extend_from_slice(&buf[0..recv_bytes as usize]);
+                current_count += recv_bytes as u64;
             }
 
-            let afc_data = parse_afc(operation, &response, status_only);
-            AfcResponse {
-                status: afc_data.0,
-                header: response_header,
-                data: afc_data.1,
+            if current_count < entire_len {
+                debug!(
+                    "WARNING: could not receive full packet (read {:?}, size {:?})",
+                    current_count, entire_len
+                );
+                // the rest of the packet would be read as the next header
+                return None;
             }
-        } else {
-            AfcResponse::error()
         }
-    }
-}
 
-fn parse_header(data: &[u8]) -> Option<AfcHeader> {
-    if data.len() < std::mem::size_of::<AfcHeader>() {
-        return None;
+        Some((response_header, Some(response)))
     }
-
-    let mut header = AfcHeader {
-        magic: [0; 8],
-        entire_length: LittleEndian::read_u64(&data[8..16]),
-        this_length: LittleEndian::read_u64(&data[16..24]),
-        packet_num: LittleEndian::read_u64(&data[24..32]),
-        operation: LittleEndian::read_u64(&data[32..40]),
-    };
-    header.magic.copy_from_slice(&data[0..8]);
-    Some(header)
 }
 
-fn parse_afc(operation: afc_opcode_t, data: &[u8], status_only: bool) -> (afc_error_t, Response) {
-    let status = if status_only { to_i32(data) } else { 0 };
-
-    let response = match operation {
-        afc_opcode_t::FILE_OPEN => Response::Number(to_u64(data)),
-        afc_opcode_t::READ | afc_opcode_t::WRITE => Response::Byte(data.to_vec()),
-        _ => {
-            let parts: Vec<Vec<u8>> = data
-                .split(|&b| b == 0)
-                .filter(|p| !p.is_empty())
-                .map(|p| p.to_vec())
-                .collect();
-            Response::List(to_vec_string(parts))
-        }
-    };
-    (status, response)
+/// Whether running `operation` twice leaves the device as running it once did. File
+/// pointers and locks are put back as they were before the request when the file is
+/// reopened, so reads and seeks qualify.
+fn replayable(operation: afc_opcode_t) -> bool {
+    matches!(
+        operation,
+        afc_opcode_t::READ_DIR
+            | afc_opcode_t::GET_FILE_INFO
+            | afc_opcode_t::GET_DEVINFO
+            | afc_opcode_t::GET_CON_INFO
+            | afc_opcode_t::FILE_OPEN
+            | afc_opcode_t::READ
+            | afc_opcode_t::FILE_SEEK
+            | afc_opcode_t::FILE_TELL
+            | afc_opcode_t::FILE_LOCK
+            | afc_opcode_t::FILE_CLOSE
+    )
 }
 
-pub trait t_afc_struct {
-    fn to_bytes(&self) -> Vec<u8>;
-}
-
-pub struct afc_stat_t {
-    pub filename: Vec<u8>,
-}
-impl t_afc_struct for afc_stat_t {
-    fn to_bytes(&self) -> Vec<u8> {
-        self.filename.clone()
-    }
-}
-pub struct afc_readdir_t {
-    pub filename: Vec<u8>,
-}
-impl t_afc_struct for afc_readdir_t {
-    fn to_bytes(&self) -> Vec<u8> {
-        self.filename.clone()
-    }
+/// How sending requests over the connection ended
+struct Exchange {
+    /// Status that kept the remaining requests from being sent
+    failure: Option<AfcError>,
+    /// Requests sent but never answered, if the connection was lost
+    lost: Option<Vec<usize>>,
 }
 
-pub struct afc_fopen_t {
-    pub mode: afc_file_mode_t,
-    pub filename: Vec<u8>,
-}
-impl t_afc_struct for afc_fopen_t {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = self.mode.to_le_bytes().to_vec();
-        buf.extend(&self.filename);
-        buf
-    }
-}
-
-pub struct afc_fread_t {
-    pub handle: u64,
-    pub size: u64,
-}
-impl t_afc_struct for afc_fread_t {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = self.handle.to_le_bytes().to_vec();
-        buf.extend_from_slice(&self.size.to_le_bytes());
-        buf
-    }
-}
-
-pub struct afc_fwrite_t {
-    pub handle: u64,
-    pub data: Vec<u8>,
-}
-impl t_afc_struct for afc_fwrite_t {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = self.handle.to_le_bytes().to_vec();
-        buf.extend(&self.data);
-        buf
-    }
-}
-
-pub struct afc_fclose_t {
-    pub handle: u64,
-}
-impl t_afc_struct for afc_fclose_t {
-    fn to_bytes(&self) -> Vec<u8> {
-        self.handle.to_le_bytes().to_vec()
-    }
-}
-
-pub struct afc_seek_t {
-    pub handle: u64,
-    pub whence: u64,
-    pub offset: i64,
-}
-impl t_afc_struct for afc_seek_t {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = self.handle.to_le_bytes().to_vec();
-        buf.extend_from_slice(&self.whence.to_le_bytes());
-        buf.extend_from_slice(&self.offset.to_le_bytes());
-        buf
-    }
+/// A request handed to `Client::pipeline`
+#[derive(Clone)]
+pub(crate) struct AfcRequest {
+    pub operation: afc_opcode_t,
+    pub data_len: u64,
+    pub payload: Vec<u8>,
 }
 
-pub struct afc_truncate_t {
-    pub filename: Vec<u8>,
-    pub newsize: u64,
-}
-impl t_afc_struct for afc_truncate_t {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = self.filename.clone();
-        buf.extend_from_slice(&self.newsize.to_le_bytes());
-        buf
-    }
-}
-
-pub struct afc_rm_t {
-    pub filename: Vec<u8>,
-}
-impl t_afc_struct for afc_rm_t {
-    fn to_bytes(&self) -> Vec<u8> {
-        self.filename.clone()
+impl AfcRequest {
+    pub(crate) fn new(operation: afc_opcode_t, payload: Vec<u8>) -> Self {
+        Self {
+            operation,
+            data_len: 0,
+            payload,
+        }
     }
 }
 
-pub struct afc_mkdir_t {
-    pub filename: Vec<u8>,
-}
-impl t_afc_struct for afc_mkdir_t {
-    fn to_bytes(&self) -> Vec<u8> {
-        self.filename.clone()
+fn parse_afc(
+    operation: afc_opcode_t,
+    response_op: u64,
+    data: &[u8],
+) -> (Result<(), AfcError>, Response) {
+    if response_op == afc_opcode_t::STATUS as u64 {
+        return (status(to_u64(data)), Response::None);
     }
-}
 
-#[repr(C, packed)]
-#[derive(Debug, Clone, Copy, Default)]
-pub struct AfcHeader {
-    pub magic: [u8; 8],
-    pub entire_length: u64,
-    pub this_length: u64,
-    pub packet_num: u64,
-    pub operation: u64,
+    let response = if response_op == afc_opcode_t::FILE_OPEN_RES as u64
+        || response_op == afc_opcode_t::FILE_TELL_RES as u64
+    {
+        Response::Number(to_u64(data))
+    } else {
+        match operation {
+            afc_opcode_t::READ | afc_opcode_t::WRITE => Response::Byte(data.to_vec()),
+            _ => {
+                let parts: Vec<Vec<u8>> = data
+                    .split(|&b| b == 0)
+                    .filter(|p| !p.is_empty())
+                    .map(|p| p.to_vec())
+                    .collect();
+                Response::List(to_vec_string(parts))
+            }
+        }
+    };
+    (Ok(()), response)
 }
 
-impl t_afc_struct for AfcHeader {
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(size_of::<AfcHeader>());
-        buf.extend_from_slice(&self.magic);
-        buf.write_u64::<LittleEndian>(self.entire_length).unwrap();
-        buf.write_u64::<LittleEndian>(self.this_length).unwrap();
-        buf.write_u64::<LittleEndian>(self.packet_num).unwrap();
-        buf.write_u64::<LittleEndian>(self.operation).unwrap();
-        buf
-    }
+/// Builds the FILE_SEEK request for `pos`
+fn seek_request(handle: u64, pos: SeekFrom) -> Result<AfcRequest, AfcError> {
+    let (offset, whence) = match pos {
+        SeekFrom::Start(offset) => (
+            i64::try_from(offset).map_err(|_| AfcError::InvalidArg)?,
+            SEEK_SET,
+        ),
+        SeekFrom::Current(offset) => (offset, SEEK_CUR),
+        SeekFrom::End(offset) => (offset, SEEK_END),
+    };
+    Ok(AfcRequest::new(
+        afc_opcode_t::FILE_SEEK,
+        afc_seek_t {
+            handle,
+            offset,
+            whence: whence as u64,
+        }
+        .to_bytes(),
+    ))
 }
 
 #[derive(Debug, Clone)]
-pub struct AfcResponse {
-    pub status: afc_error_t,
+pub(crate) struct AfcResponse {
+    pub status: Result<(), AfcError>,
     pub header: AfcHeader,
     pub data: Response,
 }
@@ -577,51 +1073,67 @@ pub struct AfcResponse {
 impl AfcResponse {
     pub(crate) fn error() -> Self {
         Self {
-            status: afc_error_t_AFC_E_UNKNOWN_ERROR,
+            status: Err(AfcError::UnknownError),
             header: AfcHeader::default(),
             data: Response::None,
         }
     }
 
-    pub(crate) fn error_with(status: afc_error_t) -> Self {
+    pub(crate) fn error_with(status: AfcError) -> Self {
         Self {
-            status,
+            status: Err(status),
             header: AfcHeader::default(),
             data: Response::None,
         }
     }
+
+    /// The response if the request succeeded, otherwise its status
+    pub(crate) fn into_result(self) -> Result<Self, AfcError> {
+        self.status?;
+        Ok(self)
+    }
+
+    /// The number a FILE_OPEN or FILE_TELL answered with
+    pub(crate) fn into_num(self) -> Result<u64, AfcError> {
+        extract_num(self.into_result()?).ok_or(AfcError::IoError)
+    }
+
+    /// The data a READ answered with
+    pub(crate) fn into_bytes(self) -> Result<Vec<u8>, AfcError> {
+        Ok(extract_byte(self.into_result()?).unwrap_or_default())
+    }
 }
 
 #[derive(Debug, Clone)]
-pub enum Response {
+pub(crate) enum Response {
     List(Vec<String>),
     Number(u64),
     Byte(Vec<u8>),
     None,
 }
 
-pub fn extract_list(res: AfcResponse) -> Option<Vec<String>> {
+pub(crate) fn extract_list(res: AfcResponse) -> Option<Vec<String>> {
     match res.data {
         Response::List(list) => Some(list),
         _ => None,
     }
 }
 
-pub fn extract_num(res: AfcResponse) -> Option<u64> {
+pub(crate) fn extract_num(res: AfcResponse) -> Option<u64> {
     match res.data {
         Response::Number(num) => Some(num),
         _ => None,
     }
 }
 
-pub fn extract_byte(res: AfcResponse) -> Option<Vec<u8>> {
+pub(crate) fn extract_byte(res: AfcResponse) -> Option<Vec<u8>> {
     match res.data {
         Response::Byte(byte) => Some(byte),
         _ => None,
     }
 }
 
-pub fn to_vec_string(parts: Vec<Vec<u8>>) -> Vec<String> {
+pub(crate) fn to_vec_string(parts: Vec<Vec<u8>>) -> Vec<String> {
     let mut values = Vec::new();
     for part in parts {
         if let Ok(k) = core::str::from_utf8(&part) {
@@ -631,51 +1143,273 @@ pub fn to_vec_string(parts: Vec<Vec<u8>>) -> Vec<String> {
     values
 }
 
-pub fn to_u64(parts: &[u8]) -> u64 {
-    parts[0] as _
+/// Reads a little-endian u64, zero-padding packets shorter than 8 bytes
+pub(crate) fn to_u64(parts: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    let len = parts.len().min(buf.len());
+    buf[..len].copy_from_slice(&parts[..len]);
+    LittleEndian::read_u64(&buf)
 }
 
-pub fn to_i32(parts: &[u8]) -> i32 {
-    parts[0] as _
-}
-
-pub fn to_map(data: Vec<String>) -> HashMap<String, String> {
+pub(crate) fn to_map(data: Vec<String>) -> HashMap<String, String> {
     data.chunks_exact(2)
         .map(|chunk| (chunk[0].clone(), chunk[1].clone()))
         .collect::<HashMap<_, _>>()
 }
 
-pub const EPERM: i32 = 1; /* Operation not permitted */
-pub const ENOENT: i32 = 2; /* No such file or directory */
-pub const ESRCH: i32 = 3; /* No such process */
-pub const EINTR: i32 = 4; /* Interrupted system call */
-pub const EIO: i32 = 5; /* I/O error */
-pub const ENXIO: i32 = 6; /* No such device or address */
-pub const E2BIG: i32 = 7; /* Argument list too long */
-pub const ENOEXEC: i32 = 8; /* Exec format error */
-pub const EBADF: i32 = 9; /* Bad file number */
-pub const ECHILD: i32 = 10; /* No child processes */
-pub const EAGAIN: i32 = 11; /* Try again */
-pub const ENOMEM: i32 = 12; /* Out of memory */
-pub const EACCES: i32 = 13; /* Permission denied */
-pub const EFAULT: i32 = 14; /* Bad address */
-pub const ENOTBLK: i32 = 15; /* Block device required */
-pub const EBUSY: i32 = 16; /* Device or resource busy */
-pub const EEXIST: i32 = 17; /* File exists */
-pub const EXDEV: i32 = 18; /* Cross-device link */
-pub const ENODEV: i32 = 19; /* No such device */
-pub const ENOTDIR: i32 = 20; /* Not a directory */
-pub const EISDIR: i32 = 21; /* Is a directory */
-pub const EINVAL: i32 = 22; /* Invalid argument */
-pub const ENFILE: i32 = 23; /* File table overflow */
-pub const EMFILE: i32 = 24; /* Too many open files */
-pub const ENOTTY: i32 = 25; /* Not a typewriter */
-pub const ETXTBSY: i32 = 26; /* Text file busy */
-pub const EFBIG: i32 = 27; /* File too large */
-pub const ENOSPC: i32 = 28; /* No space left on device */
-pub const ESPIPE: i32 = 29; /* Illegal seek */
-pub const EROFS: i32 = 30; /* Read-only file system */
-pub const EMLINK: i32 = 31; /* Too many links */
-pub const EPIPE: i32 = 32; /* Broken pipe */
-pub const EDOM: i32 = 33; /* Math argument out of domain of func */
-pub const ERANGE: i32 = 34; /* Math result not representable */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Fault, MockServer};
+    use std::{ffi::CString, time::Duration};
+
+    fn start() -> (MockServer, Client) {
+        crate::VERBOSE.get_or_init(|| false);
+        MockServer::start()
+    }
+
+    fn c(path: &str) -> CString {
+        CString::new(path).unwrap()
+    }
+
+    fn open(client: &Client, path: &str, mode: OpenMode) -> u64 {
+        client.open_file(path, mode).unwrap()
+    }
+
+    #[test]
+    fn write_seek_and_read_back() {
+        let (server, client) = start();
+        let handle = open(&client, "/hello.txt", OpenMode::ReadTruncate);
+
+        let data = b"hello afc";
+        client.file_write(handle, data).unwrap();
+        assert_eq!(client.file_tell(handle), Ok(9));
+
+        client.file_seek(handle, SeekFrom::Start(6)).unwrap();
+        assert_eq!(client.file_read(handle, 64).unwrap(), b"afc");
+        assert_eq!(client.file_read(handle, 64).unwrap(), b"");
+        client.file_seek(handle, SeekFrom::Current(-4)).unwrap();
+        assert_eq!(client.file_read(handle, 1).unwrap(), b" ");
+        assert_eq!(client.file_size(handle), Ok(9));
+        assert_eq!(
+            client.file_seek(handle, SeekFrom::Start(u64::MAX)),
+            Err(AfcError::InvalidArg)
+        );
+        assert_eq!(client.file_close(handle), Ok(()));
+
+        let on_disk = std::fs::read(server.root().join("hello.txt")).unwrap();
+        assert_eq!(on_disk, data);
+    }
+
+    #[test]
+    fn handles_above_255_are_not_truncated() {
+        let (_server, client) = start();
+        let mut handles = Vec::new();
+        for i in 0..300 {
+            let handle = open(&client, &format!("/many{}", i), OpenMode::ReadTruncate);
+            client.file_write(handle, i.to_string().as_bytes()).unwrap();
+            handles.push(handle);
+        }
+        assert_eq!(handles[299], 300);
+
+        // the mock numbers its handles from 1 << 32, each maps back to its own file
+        let request = AfcRequest::new(afc_opcode_t::FILE_TELL, 300u64.to_le_bytes().to_vec());
+        let device = client.handles.lock().unwrap().device_handle(&request);
+        assert_eq!(device, 0x1_0000_0000 + 299);
+        for (i, handle) in handles.into_iter().enumerate() {
+            client.file_seek(handle, SeekFrom::Start(0)).unwrap();
+            assert_eq!(
+                client.file_read(handle, 8).unwrap(),
+                i.to_string().as_bytes()
+            );
+            assert_eq!(client.file_close(handle), Ok(()));
+        }
+    }
+
+    #[test]
+    fn directories_and_file_info() {
+        let (_server, client) = start();
+        assert_eq!(
+            client.make_directory(c("/DCIM/100APPLE").as_ptr()).status,
+            Ok(())
+        );
+
+        let list = extract_list(client.read_directory(c("/DCIM").as_ptr())).unwrap();
+        assert_eq!(list, vec![".", "..", "100APPLE"]);
+
+        let info = to_map(extract_list(client.get_file_info(c("/DCIM").as_ptr())).unwrap());
+        assert_eq!(info.get("st_ifmt").unwrap(), "S_IFDIR");
+
+        let info = client.get_file_info(c("/missing").as_ptr());
+        assert_eq!(info.status, Err(AfcError::ObjectNotFound));
+        assert_eq!(AfcError::ObjectNotFound.to_errno(), ENOENT);
+    }
+
+    #[test]
+    fn pipelined_file_info_keeps_request_order() {
+        let (server, client) = start();
+        let mut paths = Vec::new();
+        for i in 0..PIPELINE_DEPTH * 2 + 5 {
+            if i % 7 != 3 {
+                std::fs::write(server.root().join(i.to_string()), vec![0; i]).unwrap();
+            }
+            paths.push(c(&format!("/{}", i)));
+        }
+
+        let infos = client.get_file_info_many(&paths);
+        assert_eq!(infos.len(), paths.len());
+        for (i, info) in infos.into_iter().enumerate() {
+            if i % 7 == 3 {
+                assert_eq!(info.status, Err(AfcError::ObjectNotFound));
+            } else {
+                let info = to_map(extract_list(info).unwrap());
+                assert_eq!(info.get("st_size").unwrap(), &i.to_string());
+            }
+        }
+        assert_eq!(client.get_device_info().status, Ok(()));
+    }
+
+    #[test]
+    fn replies_are_matched_by_packet_number() {
+        let (server, client) = start();
+        std::fs::write(server.root().join("first"), b"1").unwrap();
+        std::fs::write(server.root().join("second"), b"22").unwrap();
+
+        server.inject(Fault::Reorder);
+        let infos = client.get_file_info_many(&[c("/first"), c("/second")]);
+        let sizes: Vec<String> = infos
+            .into_iter()
+            .map(|info| to_map(extract_list(info).unwrap())["st_size"].clone())
+            .collect();
+        assert_eq!(sizes, vec!["1", "2"]);
+    }
+
+    #[test]
+    fn read_at_spans_several_reads() {
+        let (server, client) = start();
+        let data: Vec<u8> = (0..MAX_READ_SIZE * 2 + 100).map(|i| i as u8).collect();
+        std::fs::write(server.root().join("big"), &data).unwrap();
+        let handle = open(&client, "/big", OpenMode::ReadOnly);
+
+        let read = client.file_read_at(handle, 10, MAX_READ_SIZE * 3).unwrap();
+        assert_eq!(read, &data[10..]);
+
+        let read = client.file_read_at(handle, 5, 20).unwrap();
+        assert_eq!(read, &data[5..25]);
+    }
+
+    #[test]
+    fn remove_and_rename() {
+        let (server, client) = start();
+        std::fs::create_dir_all(server.root().join("a/b")).unwrap();
+
+        let info = client.remove_path(c("/a").as_ptr());
+        assert_eq!(info.status, Err(AfcError::DirNotEmpty));
+
+        let info = client.rename_path(c("/a/b").as_ptr(), c("/c").as_ptr());
+        assert_eq!(info.status, Ok(()));
+        assert!(server.root().join("c").is_dir());
+        assert_eq!(client.remove_path(c("/a").as_ptr()).status, Ok(()));
+    }
+
+    #[test]
+    fn device_info_over_tcp() {
+        crate::VERBOSE.get_or_init(|| false);
+        let (_server, client) = MockServer::start_tcp();
+        let info = to_map(extract_list(client.get_device_info()).unwrap());
+        assert_eq!(info.get("FSBlockSize").unwrap(), "4096");
+    }
+
+    #[test]
+    fn injected_faults() {
+        let (server, client) = start();
+
+        server.inject(Fault::Delay(Duration::from_millis(50)));
+        assert_eq!(client.get_device_info().status, Ok(()));
+
+        server.inject(Fault::WrongPacketNumber);
+        assert_eq!(client.get_device_info().status, Err(AfcError::UnknownError));
+
+        let (server, client) = start();
+        server.inject(Fault::DropConnection);
+        assert_eq!(client.get_device_info().status, Err(AfcError::UnknownError));
+        assert_eq!(client.get_device_info().status, Err(AfcError::MuxError));
+    }
+
+    #[test]
+    fn reconnects_and_reopens_files_where_they_were() {
+        let (server, client) = start();
+        let client = client.reconnect_with(server.connector(), Duration::from_secs(5));
+        let data: Vec<u8> = (0..100).collect();
+        std::fs::write(server.root().join("log"), &data).unwrap();
+
+        let reader = open(&client, "/log", OpenMode::ReadOnly);
+        let writer = open(&client, "/copy", OpenMode::ReadTruncate);
+        assert_eq!(client.file_read(reader, 10).unwrap(), &data[..10]);
+        client.file_write(writer, b"abc").unwrap();
+
+        server.inject(Fault::DropConnection);
+        assert_eq!(client.file_read(reader, 10).unwrap(), &data[10..20]);
+        // opened with truncation, but reopened without it
+        client.file_write(writer, b"def").unwrap();
+        client.file_close(writer).unwrap();
+        assert_eq!(
+            std::fs::read(server.root().join("copy")).unwrap(),
+            b"abcdef"
+        );
+        assert_eq!(server.request_count(afc_opcode_t::FILE_OPEN), 4);
+    }
+
+    #[test]
+    fn writes_in_flight_are_not_sent_twice() {
+        let (server, client) = start();
+        let client = client.reconnect_with(server.connector(), Duration::from_secs(5));
+        let writer = open(&client, "/append", OpenMode::Append);
+        client.file_write(writer, b"abc").unwrap();
+
+        // the write reaches the file, its reply does not reach the client
+        server.inject(Fault::LoseReply);
+        assert_eq!(client.file_write(writer, b"def"), Err(AfcError::IoError));
+        assert_eq!(client.file_write(writer, b"ghi"), Ok(()));
+        assert_eq!(
+            std::fs::read(server.root().join("append")).unwrap(),
+            b"abcdefghi"
+        );
+
+        client.make_directory(c"/dir".as_ptr());
+        server.inject(Fault::LoseReply);
+        assert_eq!(
+            client.remove_path(c"/dir".as_ptr()).status,
+            Err(AfcError::IoError)
+        );
+        assert!(!server.root().join("dir").exists());
+
+        // a lost reply to a read is asked for again
+        server.inject(Fault::LoseReply);
+        assert_eq!(client.get_file_info(c"/append".as_ptr()).status, Ok(()));
+    }
+
+    #[test]
+    fn gives_up_with_io_error_after_the_window() {
+        let (server, client) = start();
+        let window = Duration::from_millis(300);
+        let client = client.reconnect_with(Box::new(|| None), window);
+
+        server.inject(Fault::DropConnection);
+        let started = std::time::Instant::now();
+        assert_eq!(client.get_device_info().status, Err(AfcError::IoError));
+        assert!(started.elapsed() >= window);
+
+        // once the window has passed, requests fail without waiting again
+        let started = std::time::Instant::now();
+        assert_eq!(client.get_device_info().status, Err(AfcError::IoError));
+        assert!(started.elapsed() < window);
+    }
+
+    #[test]
+    fn statuses_are_read_as_results() {
+        assert_eq!(status(0), Ok(()));
+        assert_eq!(status(8), Err(AfcError::ObjectNotFound));
+        assert_eq!(status(1000), Err(AfcError::UnknownError));
+    }
+}
@@ -616,7 +616,10 @@ pub type __uid_t = ::std::os::raw::c_uint;
 pub type __gid_t = ::std::os::raw::c_uint;
 pub type __ino_t = ::std::os::raw::c_ulong;
 pub type __ino64_t = ::std::os::raw::c_ulong;
-pub type __mode_t = u64; //::std::os::raw::c_uint;
+#[cfg(windows)]
+pub type __mode_t = u64;
+#[cfg(not(windows))]
+pub type __mode_t = ::std::os::raw::c_uint;
 pub type __nlink_t = ::std::os::raw::c_ulong;
 pub type __off_t = i64;
 pub type __off64_t = u64;
@@ -1130,18 +1133,150 @@ pub struct stat {
     pub st_mode: __mode_t,
     pub st_uid: __uid_t,
     pub st_gid: __gid_t,
-    // pub __pad0: ::std::os::raw::c_int,
+    #[cfg(not(windows))]
+    pub __pad0: ::std::os::raw::c_int,
     pub st_rdev: __dev_t,
     pub st_size: __off_t,
-    // pub st_blksize: __blksize_t,
-    // pub st_blocks: __blkcnt_t,
+    #[cfg(not(windows))]
+    pub st_blksize: __blksize_t,
+    #[cfg(not(windows))]
+    pub st_blocks: __blkcnt_t,
     pub st_atim: timespec,
     pub st_mtim: timespec,
     pub st_ctim: timespec,
-    // pub __glibc_reserved: [__syscall_slong_t; 3usize],
+    #[cfg(not(windows))]
+    pub __glibc_reserved: [__syscall_slong_t; 3usize],
 }
-
+// layout on Windows
 #[test]
+#[cfg(all(windows, target_arch = "x86_64"))]
+fn bindgen_test_layout_stat() {
+    const UNINIT: ::std::mem::MaybeUninit<stat> = ::std::mem::MaybeUninit::uninit();
+    let ptr = UNINIT.as_ptr();
+    assert_eq!(
+        ::std::mem::size_of::<stat>(),
+        96usize,
+        concat!("Size of: ", stringify!(stat))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<stat>(),
+        8usize,
+        concat!("Alignment of ", stringify!(stat))
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).st_dev) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(stat),
+            "::",
+            stringify!(st_dev)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).st_ino) as usize - ptr as usize },
+        4usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(stat),
+            "::",
+            stringify!(st_ino)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).st_nlink) as usize - ptr as usize },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(stat),
+            "::",
+            stringify!(st_nlink)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).st_mode) as usize - ptr as usize },
+        16usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(stat),
+            "::",
+            stringify!(st_mode)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).st_uid) as usize - ptr as usize },
+        24usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(stat),
+            "::",
+            stringify!(st_uid)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).st_gid) as usize - ptr as usize },
+        28usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(stat),
+            "::",
+            stringify!(st_gid)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).st_rdev) as usize - ptr as usize },
+        32usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(stat),
+            "::",
+            stringify!(st_rdev)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).st_size) as usize - ptr as usize },
+        40usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(stat),
+            "::",
+            stringify!(st_size)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).st_atim) as usize - ptr as usize },
+        48usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(stat),
+            "::",
+            stringify!(st_atim)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).st_mtim) as usize - ptr as usize },
+        64usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(stat),
+            "::",
+            stringify!(st_mtim)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).st_ctim) as usize - ptr as usize },
+        80usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(stat),
+            "::",
+            stringify!(st_ctim)
+        )
+    );
+}
+// layout on Linux, as glibc defines it for x86_64
+#[test]
+#[cfg(all(not(windows), target_arch = "x86_64"))]
 fn bindgen_test_layout_stat() {
     const UNINIT: ::std::mem::MaybeUninit<stat> = ::std::mem::MaybeUninit::uninit();
     let ptr = UNINIT.as_ptr();
@@ -1306,6 +1441,7 @@ fn bindgen_test_layout_stat() {
         )
     );
 }
+
 extern "C" {
     #[link_name = "\u{1}fcntl64"]
     pub fn fcntl(
@@ -3343,6 +3479,72 @@ pub struct fuse_operations {
             idx: *mut u64,
         ) -> ::std::os::raw::c_int,
     >,
+    #[doc = " flag_nullpath_ok, flag_nopath, flag_utime_omit_ok and reserved bits"]
+    #[cfg(not(windows))]
+    pub flags: ::std::os::raw::c_uint,
+    #[doc = " Ioctl\n\n Introduced in version 2.8"]
+    #[cfg(not(windows))]
+    pub ioctl: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            cmd: ::std::os::raw::c_int,
+            arg: *mut ::std::os::raw::c_void,
+            arg2: *mut fuse_file_info,
+            flags: ::std::os::raw::c_uint,
+            data: *mut ::std::os::raw::c_void,
+        ) -> ::std::os::raw::c_int,
+    >,
+    #[doc = " Poll for IO readiness events\n\n Introduced in version 2.8"]
+    #[cfg(not(windows))]
+    pub poll: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *mut fuse_file_info,
+            ph: *mut ::std::os::raw::c_void,
+            reventsp: *mut ::std::os::raw::c_uint,
+        ) -> ::std::os::raw::c_int,
+    >,
+    #[doc = " Write contents of buffer to an open file\n\n Introduced in version 2.9"]
+    #[cfg(not(windows))]
+    pub write_buf: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            buf: *mut ::std::os::raw::c_void,
+            off: off_t,
+            arg2: *mut fuse_file_info,
+        ) -> ::std::os::raw::c_int,
+    >,
+    #[doc = " Store data from an open file in a buffer\n\n Introduced in version 2.9"]
+    #[cfg(not(windows))]
+    pub read_buf: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            bufp: *mut *mut ::std::os::raw::c_void,
+            size: size_t,
+            off: off_t,
+            arg2: *mut fuse_file_info,
+        ) -> ::std::os::raw::c_int,
+    >,
+    #[doc = " Perform BSD file locking operation\n\n The op argument will be either LOCK_SH, LOCK_EX or LOCK_UN\n\n Nonblocking requests will be indicated by ORing LOCK_NB to\n the above operations\n\n Introduced in version 2.9"]
+    #[cfg(not(windows))]
+    pub flock: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: *mut fuse_file_info,
+            op: ::std::os::raw::c_int,
+        ) -> ::std::os::raw::c_int,
+    >,
+    #[doc = " Allocates space for an open file\n\n Introduced in version 2.9.1"]
+    #[cfg(not(windows))]
+    pub fallocate: ::std::option::Option<
+        unsafe extern "C" fn(
+            arg1: *const ::std::os::raw::c_char,
+            arg2: ::std::os::raw::c_int,
+            arg3: off_t,
+            arg4: off_t,
+            arg5: *mut fuse_file_info,
+        ) -> ::std::os::raw::c_int,
+    >,
 }
 #[test]
 fn bindgen_test_layout_fuse_operations() {
@@ -3350,7 +3552,7 @@ fn bindgen_test_layout_fuse_operations() {
     let ptr = UNINIT.as_ptr();
     assert_eq!(
         ::std::mem::size_of::<fuse_operations>(),
-        304usize,
+        if cfg!(windows) { 304usize } else { 360usize },
         concat!("Size of: ", stringify!(fuse_operations))
     );
     assert_eq!(
@@ -3738,6 +3940,17 @@ fn bindgen_test_layout_fuse_operations() {
             stringify!(bmap)
         )
     );
+    #[cfg(not(windows))]
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).flock) as usize - ptr as usize },
+        344usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(fuse_operations),
+            "::",
+            stringify!(flock)
+        )
+    );
 }
 #[doc = " Extra context that may be needed by some filesystems\n\n The uid, gid and pid fields are not filled in case of a writepage\n operation."]
 #[repr(C)]
@@ -0,0 +1,168 @@
+use ifuse::FileInfo;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+type Entries<T> = Mutex<HashMap<String, (Instant, T)>>;
+
+/// Attributes and directory listings keyed by real path.
+///
+/// A zero TTL or size disables the cache.
+pub struct Cache {
+    ttl: Duration,
+    capacity: usize,
+    file_info: Entries<FileInfo>,
+    directory: Entries<Vec<String>>,
+}
+
+impl Cache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            file_info: Mutex::new(HashMap::new()),
+            directory: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn file_info(&self, path: &str) -> Option<FileInfo> {
+        self.lookup(&self.file_info, path)
+    }
+
+    pub fn insert_file_info(&self, path: &str, info: &FileInfo) {
+        self.insert(&self.file_info, path, info);
+    }
+
+    pub fn directory(&self, path: &str) -> Option<Vec<String>> {
+        self.lookup(&self.directory, path)
+    }
+
+    pub fn insert_directory(&self, path: &str, names: &[String]) {
+        self.insert(&self.directory, path, &names.to_vec());
+    }
+
+    /// Forgets the attributes of `path` alone, e.g. after its contents changed
+    pub fn forget(&self, path: &str) {
+        self.file_info.lock().unwrap().remove(path);
+    }
+
+    /// Forgets `path`, everything below it and the listing and attributes of its parent
+    pub fn invalidate(&self, path: &str) {
+        let path = path.trim_end_matches('/');
+        let parent = match path.rfind('/') {
+            Some(0) => "/",
+            Some(i) => &path[..i],
+            None => "",
+        };
+        let below = format!("{}/", path);
+        let stale = |key: &String| key == path || key == parent || key.starts_with(&below);
+        self.file_info.lock().unwrap().retain(|key, _| !stale(key));
+        self.directory.lock().unwrap().retain(|key, _| !stale(key));
+    }
+
+    fn lookup<T: Clone>(&self, map: &Entries<T>, path: &str) -> Option<T> {
+        let mut map = map.lock().unwrap();
+        match map.get(path) {
+            Some((expires, value)) if *expires > Instant::now() => Some(value.clone()),
+            Some(_) => {
+                map.remove(path);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert<T: Clone>(&self, map: &Entries<T>, path: &str, value: &T) {
+        if self.ttl.is_zero() || self.capacity == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut map = map.lock().unwrap();
+        if map.len() >= self.capacity && !map.contains_key(path) {
+            map.retain(|_, (expires, _)| *expires > now);
+        }
+        if map.len() >= self.capacity && !map.contains_key(path) {
+            // every entry shares the TTL, so the first to expire is the oldest
+            if let Some(oldest) = map
+                .iter()
+                .min_by_key(|(_, (expires, _))| *expires)
+                .map(|(key, _)| key.clone())
+            {
+                map.remove(&oldest);
+            }
+        }
+        map.insert(path.to_string(), (now + self.ttl, value.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ifuse::FileType;
+    use std::time::UNIX_EPOCH;
+
+    fn info(size: u64) -> FileInfo {
+        FileInfo {
+            file_type: FileType::File,
+            size,
+            blocks: 0,
+            nlink: 1,
+            modified: UNIX_EPOCH,
+            created: UNIX_EPOCH,
+            link_target: None,
+        }
+    }
+
+    #[test]
+    fn entries_expire() {
+        let cache = Cache::new(Duration::from_millis(50), 10);
+        cache.insert_file_info("/a", &info(1));
+        assert!(cache.file_info("/a").is_some());
+        std::thread::sleep(Duration::from_millis(80));
+        assert!(cache.file_info("/a").is_none());
+    }
+
+    #[test]
+    fn disabled_cache_stores_nothing() {
+        let cache = Cache::new(Duration::ZERO, 10);
+        cache.insert_file_info("/a", &info(0));
+        assert!(cache.file_info("/a").is_none());
+
+        let cache = Cache::new(Duration::from_secs(60), 0);
+        cache.insert_directory("/", &[]);
+        assert!(cache.directory("/").is_none());
+    }
+
+    #[test]
+    fn oldest_entry_is_evicted_when_full() {
+        let cache = Cache::new(Duration::from_secs(60), 2);
+        cache.insert_file_info("/a", &info(0));
+        std::thread::sleep(Duration::from_millis(2));
+        cache.insert_file_info("/b", &info(0));
+        cache.insert_file_info("/c", &info(0));
+        assert!(cache.file_info("/a").is_none());
+        assert!(cache.file_info("/b").is_some());
+        assert!(cache.file_info("/c").is_some());
+    }
+
+    #[test]
+    fn invalidate_drops_path_children_and_parent() {
+        let cache = Cache::new(Duration::from_secs(60), 10);
+        for path in ["/", "/d", "/d/f", "/d/sub/g", "/dx", "/other"] {
+            cache.insert_file_info(path, &info(0));
+            cache.insert_directory(path, &[]);
+        }
+        cache.invalidate("/d");
+        for path in ["/", "/d", "/d/f", "/d/sub/g"] {
+            assert!(cache.file_info(path).is_none(), "{}", path);
+            assert!(cache.directory(path).is_none(), "{}", path);
+        }
+        for path in ["/dx", "/other"] {
+            assert!(cache.file_info(path).is_some(), "{}", path);
+            assert!(cache.directory(path).is_some(), "{}", path);
+        }
+    }
+}
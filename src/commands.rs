@@ -0,0 +1,405 @@
+use crate::real_path;
+use ifuse::{AfcError, AfcFile, Client, DirEntry, FileInfo, FileType};
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, BufReader, BufWriter, ErrorKind, Write},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+/// Bytes moved per request by `cat`, `pull` and `push`
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Prints the entries of `path`, with type, size and modification time if `long`, and
+/// the directories below it if `recursive`
+pub fn ls(
+    client: &Client,
+    path: &str,
+    long: bool,
+    recursive: bool,
+    out: &mut dyn Write,
+) -> Result<(), AfcError> {
+    let info = stat(client, path)?;
+    if !info.is_dir() {
+        return print_entry(out, path, &info, long);
+    }
+
+    let entries = entries(client, path)?;
+    if recursive {
+        output(writeln!(out, "{}:", path))?;
+    }
+    for entry in &entries {
+        print_entry(out, &entry.name, &entry.info, long)?;
+    }
+    if recursive {
+        for entry in entries.iter().filter(|entry| entry.info.is_dir()) {
+            output(writeln!(out))?;
+            ls(client, &join(path, &entry.name), long, true, out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Prints every attribute the device reports for `path`
+pub fn stat_path(client: &Client, path: &str, out: &mut dyn Write) -> Result<(), AfcError> {
+    let attributes = client
+        .attributes(remote(path))
+        .map_err(|status| failed(path, status))?;
+    for (key, value) in attributes {
+        output(writeln!(out, "{}: {}", key, value))?;
+    }
+    Ok(())
+}
+
+/// Writes the contents of `path` to `out`
+pub fn cat(client: &Client, path: &str, out: &mut dyn Write) -> Result<(), AfcError> {
+    let file = AfcFile::open(client, remote(path)).map_err(|status| failed(path, status))?;
+    io::copy(&mut BufReader::with_capacity(CHUNK_SIZE, file), out)
+        .map(drop)
+        .map_err(|e| copy_error(path, Path::new("-"), e))
+}
+
+/// Copies `path` from the device to `local`, into it if it is a directory
+pub fn pull(client: &Client, path: &str, local: &Path, recursive: bool) -> Result<(), AfcError> {
+    let info = stat(client, path)?;
+    let target = if local.is_dir() {
+        local.join(base_name(path))
+    } else {
+        local.to_path_buf()
+    };
+    pull_entry(client, path, &info, &target, recursive)
+}
+
+fn pull_entry(
+    client: &Client,
+    path: &str,
+    info: &FileInfo,
+    local: &Path,
+    recursive: bool,
+) -> Result<(), AfcError> {
+    if !info.is_dir() {
+        let mut file = File::create(local).map_err(|e| local_error(local, e))?;
+        return cat(client, path, &mut file);
+    }
+    if !recursive {
+        return Err(failed(path, AfcError::ObjectIsDir));
+    }
+
+    std::fs::create_dir_all(local).map_err(|e| local_error(local, e))?;
+    for entry in entries(client, path)? {
+        let local = local.join(&entry.name);
+        pull_entry(client, &join(path, &entry.name), &entry.info, &local, true)?;
+    }
+    Ok(())
+}
+
+/// Copies `local` to `path` on the device, into it if it is a directory
+pub fn push(client: &Client, local: &Path, path: &str, recursive: bool) -> Result<(), AfcError> {
+    let target = if client.stat(remote(path)).is_ok_and(|info| info.is_dir()) {
+        join(
+            path,
+            &local.file_name().unwrap_or_default().to_string_lossy(),
+        )
+    } else {
+        path.to_string()
+    };
+    push_entry(client, local, &target, recursive)
+}
+
+fn push_entry(client: &Client, local: &Path, path: &str, recursive: bool) -> Result<(), AfcError> {
+    let meta = std::fs::metadata(local).map_err(|e| local_error(local, e))?;
+    if !meta.is_dir() {
+        return upload(client, local, path);
+    }
+    if !recursive {
+        return Err(failed(&local.to_string_lossy(), AfcError::ObjectIsDir));
+    }
+
+    mkdir(client, path)?;
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(local).map_err(|e| local_error(local, e))? {
+        names.push(entry.map_err(|e| local_error(local, e))?.file_name());
+    }
+    names.sort();
+    for name in names {
+        push_entry(
+            client,
+            &local.join(&name),
+            &join(path, &name.to_string_lossy()),
+            true,
+        )?;
+    }
+    Ok(())
+}
+
+fn upload(client: &Client, local: &Path, path: &str) -> Result<(), AfcError> {
+    let mut file = File::open(local).map_err(|e| local_error(local, e))?;
+    let remote_file =
+        AfcFile::create(client, remote(path)).map_err(|status| failed(path, status))?;
+    let mut writer = BufWriter::with_capacity(CHUNK_SIZE, remote_file);
+    io::copy(&mut file, &mut writer).map_err(|e| copy_error(path, local, e))?;
+    let remote_file = writer
+        .into_inner()
+        .map_err(|e| copy_error(path, local, e.into_error()))?;
+    remote_file.close().map_err(|status| failed(path, status))
+}
+
+/// Removes `path`, together with everything in it if `recursive`
+pub fn rm(client: &Client, path: &str, recursive: bool) -> Result<(), AfcError> {
+    if !recursive {
+        return client
+            .remove(remote(path))
+            .map_err(|status| failed(path, status));
+    }
+
+    match client.remove_all(remote(path)) {
+        // older devices only remove empty directories
+        Err(AfcError::UnknownPacketType) => {}
+        res => return res.map_err(|status| failed(path, status)),
+    }
+    if stat(client, path)?.is_dir() {
+        for entry in entries(client, path)? {
+            rm(client, &join(path, &entry.name), true)?;
+        }
+    }
+    client
+        .remove(remote(path))
+        .map_err(|status| failed(path, status))
+}
+
+pub fn mkdir(client: &Client, path: &str) -> Result<(), AfcError> {
+    client
+        .create_dir(remote(path))
+        .map_err(|status| failed(path, status))
+}
+
+pub fn mv(client: &Client, from: &str, to: &str) -> Result<(), AfcError> {
+    client
+        .rename(remote(from), remote(to))
+        .map_err(|status| failed(from, status))
+}
+
+/// Device path for a path given on the command line, inside Documents in house_arrest mode
+fn remote(path: &str) -> String {
+    let path = CString::new(path).unwrap_or_default();
+    real_path(path.as_ptr())
+}
+
+pub(crate) fn join(dir: &str, name: &str) -> String {
+    if dir.ends_with('/') {
+        format!("{}{}", dir, name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+fn base_name(path: &str) -> &str {
+    path.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or(path)
+}
+
+fn stat(client: &Client, path: &str) -> Result<FileInfo, AfcError> {
+    client
+        .stat(remote(path))
+        .map_err(|status| failed(path, status))
+}
+
+fn entries(client: &Client, path: &str) -> Result<Vec<DirEntry>, AfcError> {
+    client
+        .read_dir(remote(path))
+        .map_err(|status| failed(path, status))
+}
+
+fn print_entry(
+    out: &mut dyn Write,
+    name: &str,
+    info: &FileInfo,
+    long: bool,
+) -> Result<(), AfcError> {
+    if !long {
+        return output(writeln!(out, "{}", name));
+    }
+
+    let kind = match info.file_type {
+        FileType::Directory => 'd',
+        FileType::Symlink => 'l',
+        _ => '-',
+    };
+    let mtime = info.modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut line = format!(
+        "{} {:>12} {} {}",
+        kind,
+        info.size,
+        format_time(mtime.as_secs()),
+        name
+    );
+    if let Some(target) = &info.link_target {
+        line.push_str(" -> ");
+        line.push_str(target);
+    }
+    output(writeln!(out, "{}", line))
+}
+
+/// `YYYY-MM-DD HH:MM` in UTC for seconds since the epoch
+fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let (hour, minute) = (secs % 86400 / 3600, secs % 3600 / 60);
+
+    // civil date from days since 1970-01-01, after Howard Hinnant's days_from_civil
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year, month, day, hour, minute
+    )
+}
+
+/// Reports `status` for `path` and returns it
+fn failed(path: &str, status: AfcError) -> AfcError {
+    eprintln!("{}: {}", path, status);
+    status
+}
+
+/// Reports a local file error and picks the AFC status closest to it
+fn local_error(path: &Path, e: std::io::Error) -> AfcError {
+    eprintln!("{}: {}", path.display(), e);
+    match e.kind() {
+        ErrorKind::NotFound => AfcError::ObjectNotFound,
+        ErrorKind::PermissionDenied => AfcError::PermDenied,
+        ErrorKind::AlreadyExists => AfcError::ObjectExists,
+        _ => AfcError::IoError,
+    }
+}
+
+/// Reports a failed copy between `path` on the device and `local`, whichever side
+/// it came from
+fn copy_error(path: &str, local: &Path, e: io::Error) -> AfcError {
+    match AfcError::from_io(&e) {
+        Some(status) => failed(path, status),
+        None => local_error(local, e),
+    }
+}
+
+fn output(res: std::io::Result<()>) -> Result<(), AfcError> {
+    res.map_err(|e| local_error(Path::new("-"), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+
+    fn start() -> (MockServer, Client) {
+        crate::VERBOSE.get_or_init(|| false);
+        crate::IN_HOUSE_ARREST.get_or_init(|| false);
+        MockServer::start()
+    }
+
+    fn local_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ifuse-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn ls_lists_recursively_in_name_order() {
+        let (server, client) = start();
+        std::fs::create_dir_all(server.root().join("DCIM/100APPLE")).unwrap();
+        std::fs::write(server.root().join("DCIM/100APPLE/IMG_1.JPG"), b"jpeg").unwrap();
+        std::fs::write(server.root().join("DCIM/b.txt"), b"").unwrap();
+
+        let mut out = Vec::new();
+        ls(&client, "/DCIM", false, true, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "/DCIM:\n100APPLE\nb.txt\n\n/DCIM/100APPLE:\nIMG_1.JPG\n"
+        );
+
+        let mut out = Vec::new();
+        ls(&client, "/DCIM/100APPLE/IMG_1.JPG", true, false, &mut out).unwrap();
+        let line = String::from_utf8(out).unwrap();
+        assert!(line.starts_with("-            4 "), "{}", line);
+        assert_eq!(
+            ls(&client, "/missing", false, false, &mut Vec::new()),
+            Err(AfcError::ObjectNotFound)
+        );
+    }
+
+    #[test]
+    fn push_and_pull_round_trip_a_tree() {
+        let (server, client) = start();
+        let local = local_dir("push");
+        std::fs::create_dir_all(local.join("album/sub")).unwrap();
+        let big: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        std::fs::write(local.join("album/big.bin"), &big).unwrap();
+        std::fs::write(local.join("album/sub/note.txt"), b"note").unwrap();
+
+        assert_eq!(
+            push(&client, &local.join("album"), "/", false),
+            Err(AfcError::ObjectIsDir)
+        );
+        push(&client, &local.join("album"), "/", true).unwrap();
+        assert_eq!(
+            std::fs::read(server.root().join("album/big.bin")).unwrap(),
+            big
+        );
+
+        let back = local_dir("pull");
+        pull(&client, "/album", &back, true).unwrap();
+        assert_eq!(std::fs::read(back.join("album/big.bin")).unwrap(), big);
+        assert_eq!(
+            std::fs::read(back.join("album/sub/note.txt")).unwrap(),
+            b"note"
+        );
+
+        let mut out = Vec::new();
+        cat(&client, "/album/sub/note.txt", &mut out).unwrap();
+        assert_eq!(out, b"note");
+
+        let _ = std::fs::remove_dir_all(local);
+        let _ = std::fs::remove_dir_all(back);
+    }
+
+    #[test]
+    fn rm_mkdir_and_mv() {
+        let (server, client) = start();
+        mkdir(&client, "/a/b").unwrap();
+        std::fs::write(server.root().join("a/b/f"), b"x").unwrap();
+        mv(&client, "/a", "/c").unwrap();
+
+        assert_eq!(rm(&client, "/c", false), Err(AfcError::DirNotEmpty));
+        rm(&client, "/c", true).unwrap();
+        assert!(!server.root().join("c").exists());
+    }
+
+    #[test]
+    fn rm_walks_the_tree_on_older_devices() {
+        let (server, client) = start();
+        server.unsupported(crate::protocol::afc_opcode_t::REMOVE_PATH_AND_CONTENTS);
+        mkdir(&client, "/a/b").unwrap();
+        std::fs::write(server.root().join("a/b/f"), b"x").unwrap();
+        std::fs::write(server.root().join("a/g"), b"x").unwrap();
+
+        rm(&client, "/a", true).unwrap();
+        assert!(!server.root().join("a").exists());
+    }
+
+    #[test]
+    fn times_are_formatted_in_utc() {
+        assert_eq!(format_time(0), "1970-01-01 00:00");
+        assert_eq!(format_time(951_782_400 + 3_660), "2000-02-29 01:01");
+        assert_eq!(format_time(1_700_000_000), "2023-11-14 22:13");
+    }
+}
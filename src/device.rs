@@ -0,0 +1,112 @@
+#![allow(non_upper_case_globals)]
+use crate::{
+    idevice_connection_type_CONNECTION_NETWORK, idevice_connection_type_CONNECTION_USBMUXD,
+    idevice_device_list_extended_free, idevice_error_t_IDEVICE_E_SUCCESS, idevice_free,
+    idevice_get_device_list_extended, idevice_get_udid, idevice_info_t, idevice_new_with_options,
+    idevice_options, idevice_options_IDEVICE_LOOKUP_NETWORK, idevice_options_IDEVICE_LOOKUP_USBMUX,
+    idevice_t, lockdownd_client_free, lockdownd_client_new, lockdownd_client_t,
+    lockdownd_error_t_LOCKDOWN_E_SUCCESS, lockdownd_get_device_name,
+};
+use std::{
+    ffi::{CStr, CString},
+    mem::MaybeUninit,
+};
+
+/// Lookup options for `idevice_new_with_options`, both transports unless restricted
+pub(crate) fn lookup_options(usb: bool, network: bool) -> idevice_options {
+    match (usb, network) {
+        (true, false) => idevice_options_IDEVICE_LOOKUP_USBMUX,
+        (false, true) => idevice_options_IDEVICE_LOOKUP_NETWORK,
+        _ => idevice_options_IDEVICE_LOOKUP_USBMUX | idevice_options_IDEVICE_LOOKUP_NETWORK,
+    }
+}
+
+pub(crate) fn print_devices(options: idevice_options) {
+    let mut list = MaybeUninit::<*mut idevice_info_t>::zeroed();
+    let mut count = 0;
+    let res = unsafe { idevice_get_device_list_extended(list.as_mut_ptr(), &mut count) };
+    if res != idevice_error_t_IDEVICE_E_SUCCESS {
+        eprintln!("Cannot get device list:{:?}", res);
+        return;
+    }
+
+    let list = unsafe { list.assume_init() };
+    for i in 0..count as usize {
+        let info = unsafe { &**list.add(i) };
+        let (conn_type, lookup) = match info.conn_type {
+            idevice_connection_type_CONNECTION_USBMUXD => {
+                ("USB", idevice_options_IDEVICE_LOOKUP_USBMUX)
+            }
+            idevice_connection_type_CONNECTION_NETWORK => {
+                ("Network", idevice_options_IDEVICE_LOOKUP_NETWORK)
+            }
+            _ => ("Unknown", 0),
+        };
+        if options & lookup == 0 {
+            continue;
+        }
+
+        let udid = unsafe { CStr::from_ptr(info.udid) };
+        let name = device_name(udid, lookup).unwrap_or_default();
+        println!("{} ({}) {}", udid.to_string_lossy(), conn_type, name);
+    }
+
+    unsafe { idevice_device_list_extended_free(list) };
+}
+
+/// UDID of an opened device, to find the same one again later
+pub(crate) fn udid(device: idevice_t) -> Option<CString> {
+    let mut udid = std::ptr::null_mut();
+    let res = unsafe { idevice_get_udid(device, &mut udid) };
+    let owned = if res == idevice_error_t_IDEVICE_E_SUCCESS && !udid.is_null() {
+        Some(unsafe { CStr::from_ptr(udid) }.to_owned())
+    } else {
+        None
+    };
+    // malloc'ed by libimobiledevice; on Windows freeing it needs the library's own C
+    // runtime, so it is left to the process exit there
+    #[cfg(not(windows))]
+    unsafe {
+        libc::free(udid.cast())
+    };
+    owned
+}
+
+pub(crate) fn device_name(udid: &CStr, options: idevice_options) -> Option<String> {
+    let mut device = MaybeUninit::<idevice_t>::zeroed();
+    let res = unsafe { idevice_new_with_options(device.as_mut_ptr(), udid.as_ptr(), options) };
+    if res != idevice_error_t_IDEVICE_E_SUCCESS {
+        return None;
+    }
+    let device = unsafe { device.assume_init() };
+
+    let mut client = MaybeUninit::<lockdownd_client_t>::zeroed();
+    let label = CString::new("dokanifuse").unwrap();
+    let res = unsafe { lockdownd_client_new(device, client.as_mut_ptr(), label.as_ptr()) };
+    if res != lockdownd_error_t_LOCKDOWN_E_SUCCESS {
+        unsafe { idevice_free(device) };
+        return None;
+    }
+    let client = unsafe { client.assume_init() };
+
+    let mut name = std::ptr::null_mut();
+    let res = unsafe { lockdownd_get_device_name(client, &mut name) };
+    let owned = if res == lockdownd_error_t_LOCKDOWN_E_SUCCESS && !name.is_null() {
+        Some(
+            unsafe { CStr::from_ptr(name) }
+                .to_string_lossy()
+                .to_string(),
+        )
+    } else {
+        None
+    };
+    // malloc'ed by libimobiledevice, freed like the UDID
+    #[cfg(not(windows))]
+    unsafe {
+        libc::free(name.cast())
+    };
+
+    unsafe { lockdownd_client_free(client) };
+    unsafe { idevice_free(device) };
+    owned
+}
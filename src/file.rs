@@ -0,0 +1,199 @@
+use crate::afc::{AfcError, Client, LockOp, OpenMode};
+use std::{
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    mem::ManuallyDrop,
+    path::Path,
+};
+
+/// A file open on the device, closed when dropped.
+///
+/// Reads, writes and seeks go straight to the device, one request each, so wrap it
+/// in a `BufReader` or `BufWriter` for small transfers. Errors are `io::Error`s
+/// carrying the `AfcError`, see [`AfcError::from_io`].
+pub struct AfcFile<'a> {
+    client: &'a Client,
+    handle: u64,
+}
+
+impl<'a> AfcFile<'a> {
+    /// Opens `path` for reading
+    pub fn open(client: &'a Client, path: impl AsRef<Path>) -> Result<Self, AfcError> {
+        Self::with_mode(client, path, OpenMode::ReadOnly)
+    }
+
+    /// Opens `path` for writing, creating it or cutting it to nothing
+    pub fn create(client: &'a Client, path: impl AsRef<Path>) -> Result<Self, AfcError> {
+        Self::with_mode(client, path, OpenMode::ReadTruncate)
+    }
+
+    /// Opens `path` the way `mode` says
+    pub fn with_mode(
+        client: &'a Client,
+        path: impl AsRef<Path>,
+        mode: OpenMode,
+    ) -> Result<Self, AfcError> {
+        let handle = client.open_file(path, mode)?;
+        Ok(Self { client, handle })
+    }
+
+    /// Handle the `file_*` requests of the client take for this file
+    pub fn handle(&self) -> u64 {
+        self.handle
+    }
+
+    /// Size of the file; the file pointer is left where it was
+    pub fn len(&mut self) -> Result<u64, AfcError> {
+        let position = self.client.file_tell(self.handle)?;
+        let size = self.client.file_size(self.handle);
+        let back = self
+            .client
+            .file_seek(self.handle, SeekFrom::Start(position));
+        let size = size?;
+        back.map(|_| size)
+    }
+
+    /// Whether the file holds no data
+    pub fn is_empty(&mut self) -> Result<bool, AfcError> {
+        self.len().map(|size| size == 0)
+    }
+
+    /// Grows or cuts the file to `size` bytes, like `File::set_len`
+    pub fn set_len(&self, size: u64) -> Result<(), AfcError> {
+        self.client.file_set_size(self.handle, size)
+    }
+
+    /// Takes or releases an advisory lock on the file
+    pub fn lock(&self, operation: LockOp) -> Result<(), AfcError> {
+        self.client.file_lock(self.handle, operation)
+    }
+
+    /// Closes the file, reporting what dropping it would ignore
+    pub fn close(self) -> Result<(), AfcError> {
+        let file = ManuallyDrop::new(self);
+        file.client.file_close(file.handle)
+    }
+}
+
+impl Read for AfcFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let data = self.client.file_read_bulk(self.handle, buf.len() as u64)?;
+        // never more than asked for, but do not trust the device on it
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+}
+
+impl Write for AfcFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.client.file_write(self.handle, buf)?;
+        Ok(buf.len())
+    }
+
+    /// Writes are sent as they are made, there is nothing to flush
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for AfcFile<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.client.file_seek(self.handle, pos)?;
+        Ok(self.client.file_tell(self.handle)?)
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+        Ok(self.client.file_tell(self.handle)?)
+    }
+}
+
+impl Drop for AfcFile<'_> {
+    fn drop(&mut self) {
+        let _ = self.client.file_close(self.handle);
+    }
+}
+
+impl From<AfcError> for io::Error {
+    fn from(status: AfcError) -> Self {
+        let kind = match status {
+            AfcError::ObjectNotFound => ErrorKind::NotFound,
+            AfcError::PermDenied => ErrorKind::PermissionDenied,
+            AfcError::ObjectExists => ErrorKind::AlreadyExists,
+            AfcError::InvalidArg => ErrorKind::InvalidInput,
+            AfcError::OpTimeout => ErrorKind::TimedOut,
+            AfcError::OpWouldBlock => ErrorKind::WouldBlock,
+            AfcError::NoMem | AfcError::NoResources => ErrorKind::OutOfMemory,
+            AfcError::OpNotSupported | AfcError::UnknownPacketType => ErrorKind::Unsupported,
+            AfcError::ServiceNotConnected | AfcError::MuxError => ErrorKind::NotConnected,
+            _ => ErrorKind::Other,
+        };
+        io::Error::new(kind, status)
+    }
+}
+
+impl AfcError {
+    /// The status an `io::Error` made from an `AfcError` carries, `None` for other errors
+    pub fn from_io(error: &io::Error) -> Option<AfcError> {
+        error.get_ref()?.downcast_ref::<AfcError>().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock::MockServer, protocol::afc_opcode_t};
+    use std::io::{BufRead, BufReader, BufWriter};
+
+    #[test]
+    fn std_io_against_the_mock() {
+        crate::VERBOSE.get_or_init(|| false);
+        let (server, client) = MockServer::start();
+
+        let mut file = BufWriter::new(AfcFile::create(&client, "/notes.txt").unwrap());
+        io::copy(&mut &b"one\ntwo\nthree\n"[..], &mut file).unwrap();
+        file.into_inner().ok().unwrap().close().unwrap();
+        assert_eq!(
+            std::fs::read(server.root().join("notes.txt")).unwrap(),
+            b"one\ntwo\nthree\n"
+        );
+
+        let file = AfcFile::open(&client, "/notes.txt").unwrap();
+        let lines: Vec<String> = BufReader::new(file).lines().map(Result::unwrap).collect();
+        assert_eq!(lines, ["one", "two", "three"]);
+
+        let mut file = AfcFile::open(&client, "/notes.txt").unwrap();
+        assert_eq!(file.seek(SeekFrom::End(-6)).unwrap(), 8);
+        assert_eq!(file.len().unwrap(), 14);
+        assert_eq!(file.stream_position().unwrap(), 8);
+        let mut rest = String::new();
+        file.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "three\n");
+        file.seek(SeekFrom::Current(-10)).unwrap();
+        let mut word = [0; 3];
+        file.read_exact(&mut word).unwrap();
+        assert_eq!(&word, b"two");
+        drop(file);
+        assert_eq!(server.request_count(afc_opcode_t::FILE_CLOSE), 3);
+    }
+
+    #[test]
+    fn errors_keep_their_status() {
+        crate::VERBOSE.get_or_init(|| false);
+        let (_server, client) = MockServer::start();
+        assert!(matches!(
+            AfcFile::open(&client, "/missing"),
+            Err(AfcError::ObjectNotFound)
+        ));
+
+        let error = io::Error::from(AfcError::PermDenied);
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(AfcError::from_io(&error), Some(AfcError::PermDenied));
+        assert_eq!(AfcError::from_io(&io::Error::other("local")), None);
+    }
+}
@@ -0,0 +1,348 @@
+use ifuse::{AfcError, Client, LockOp};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// State of the files open through the mount, keyed by FUSE file handle
+pub struct OpenFiles {
+    read_ahead: usize,
+    write_behind: usize,
+    files: Mutex<HashMap<u64, Arc<Mutex<OpenFile>>>>,
+}
+
+impl OpenFiles {
+    /// `read_ahead` and `write_behind` are in bytes, 0 turns either off
+    pub fn new(read_ahead: usize, write_behind: usize) -> Self {
+        Self {
+            read_ahead,
+            write_behind,
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn insert(&self, fh: u64, append: bool) {
+        let file = OpenFile::new(self.read_ahead, self.write_behind, append);
+        self.files
+            .lock()
+            .unwrap()
+            .insert(fh, Arc::new(Mutex::new(file)));
+    }
+
+    pub fn get(&self, fh: u64) -> Arc<Mutex<OpenFile>> {
+        self.files
+            .lock()
+            .unwrap()
+            .entry(fh)
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(OpenFile::new(
+                    self.read_ahead,
+                    self.write_behind,
+                    false,
+                )))
+            })
+            .clone()
+    }
+
+    pub fn remove(&self, fh: u64) -> Option<Arc<Mutex<OpenFile>>> {
+        self.files.lock().unwrap().remove(&fh)
+    }
+}
+
+/// Read-ahead and write-behind buffers of one open file.
+///
+/// `position` is where the device's file pointer is known to be, so a request that
+/// continues where the previous one ended is sent without a FILE_SEEK.
+pub struct OpenFile {
+    read_ahead: usize,
+    write_behind: usize,
+    append: bool,
+    position: Option<u64>,
+    next_read: Option<u64>,
+    end: Option<u64>,
+    read_offset: u64,
+    read_buf: Vec<u8>,
+    write_offset: u64,
+    write_buf: Vec<u8>,
+}
+
+impl OpenFile {
+    fn new(read_ahead: usize, write_behind: usize, append: bool) -> Self {
+        Self {
+            read_ahead,
+            write_behind,
+            append,
+            position: None,
+            next_read: None,
+            end: None,
+            read_offset: 0,
+            read_buf: Vec::new(),
+            write_offset: 0,
+            write_buf: Vec::new(),
+        }
+    }
+
+    /// Lock this handle holds on the device, if any, as the client followed it
+    /// across reconnects
+    pub fn held_lock(&self, client: &Client, handle: u64) -> Option<LockOp> {
+        client.held_lock(handle)
+    }
+
+    /// Takes, converts or releases the device lock of the file without waiting
+    pub fn lock(
+        &mut self,
+        client: &Client,
+        handle: u64,
+        operation: LockOp,
+    ) -> Result<(), AfcError> {
+        client.file_lock(handle, operation)
+    }
+
+    pub fn read(
+        &mut self,
+        client: &Client,
+        handle: u64,
+        offset: u64,
+        size: usize,
+    ) -> Result<Vec<u8>, AfcError> {
+        self.flush(client, handle)?;
+
+        let end = offset + size as u64;
+        if offset >= self.read_offset && end <= self.read_offset + self.read_buf.len() as u64 {
+            let start = (offset - self.read_offset) as usize;
+            self.next_read = Some(end);
+            return Ok(self.read_buf[start..start + size].to_vec());
+        }
+
+        // only a read continuing the previous one, short of the end, is worth fetching ahead for
+        let fetch = if self.next_read == Some(offset) && self.end.is_none_or(|end| offset < end) {
+            size.max(self.read_ahead)
+        } else {
+            size
+        };
+        let read = if self.position == Some(offset) {
+            client.file_read_bulk(handle, fetch as _)
+        } else {
+            client.file_read_at(handle, offset, fetch as _)
+        };
+        let mut data = read.inspect_err(|_| self.position = None)?;
+        self.position = Some(offset + data.len() as u64);
+        let len = data.len().min(size);
+        self.next_read = Some(offset + len as u64);
+        if data.len() < fetch {
+            self.end = Some(offset + data.len() as u64);
+        }
+        if data.len() > size {
+            let result = data[..len].to_vec();
+            self.read_offset = offset;
+            self.read_buf = data;
+            Ok(result)
+        } else {
+            self.read_buf.clear();
+            data.truncate(len);
+            Ok(data)
+        }
+    }
+
+    /// Queues `data` for `offset`; device errors surface from a later `flush`
+    pub fn write(
+        &mut self,
+        client: &Client,
+        handle: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), AfcError> {
+        // anything read ahead may be overwritten, and the file may grow
+        self.read_buf.clear();
+        self.end = None;
+
+        let continues = offset == self.write_offset + self.write_buf.len() as u64;
+        if self.write_buf.is_empty() || !continues {
+            self.flush(client, handle)?;
+            self.write_offset = offset;
+        }
+        self.write_buf.extend_from_slice(data);
+
+        if self.write_buf.len() >= self.write_behind {
+            self.flush(client, handle)?;
+        }
+        Ok(())
+    }
+
+    /// Size of the file including the writes not sent yet
+    pub fn size(&mut self, client: &Client, handle: u64) -> Result<u64, AfcError> {
+        let size = client
+            .file_size(handle)
+            .inspect_err(|_| self.position = None)?;
+        self.position = Some(size);
+
+        if self.write_buf.is_empty() {
+            Ok(size)
+        } else {
+            Ok(size.max(self.write_offset + self.write_buf.len() as u64))
+        }
+    }
+
+    /// Truncates or extends the open file, after sending the queued writes
+    pub fn set_size(&mut self, client: &Client, handle: u64, size: u64) -> Result<(), AfcError> {
+        self.flush(client, handle)?;
+        self.read_buf.clear();
+        self.end = None;
+
+        client.file_set_size(handle, size)
+    }
+
+    /// Sends the queued writes to the device
+    pub fn flush(&mut self, client: &Client, handle: u64) -> Result<(), AfcError> {
+        if self.write_buf.is_empty() {
+            return Ok(());
+        }
+
+        let data = std::mem::take(&mut self.write_buf);
+        let written = if self.position == Some(self.write_offset) {
+            client.file_write(handle, &data)
+        } else {
+            client.file_write_at(handle, self.write_offset, &data)
+        };
+        written.inspect_err(|_| self.position = None)?;
+
+        // in append mode the device writes at the end, wherever the pointer was
+        self.position = if self.append {
+            None
+        } else {
+            Some(self.write_offset + data.len() as u64)
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::{Fault, MockServer},
+        protocol::afc_opcode_t,
+    };
+    use ifuse::OpenMode;
+    use std::time::Duration;
+
+    const MIB: usize = 1 << 20;
+
+    fn open(client: &Client, path: &str, mode: OpenMode) -> u64 {
+        client.open_file(path, mode).unwrap()
+    }
+
+    #[test]
+    fn sequential_reads_are_served_from_read_ahead() {
+        crate::VERBOSE.get_or_init(|| false);
+        let (server, client) = MockServer::start();
+        let data: Vec<u8> = (0..3 * MIB).map(|i| (i % 251) as u8).collect();
+        std::fs::write(server.root().join("video"), &data).unwrap();
+        let handle = open(&client, "/video", OpenMode::ReadOnly);
+
+        let mut file = OpenFile::new(MIB, MIB, false);
+        let mut read = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let chunk = file.read(&client, handle, offset as u64, 4096).unwrap();
+            assert!(!chunk.is_empty());
+            offset += chunk.len();
+            read.extend(chunk);
+        }
+        assert_eq!(read, data);
+        assert!(file
+            .read(&client, handle, offset as u64, 4096)
+            .unwrap()
+            .is_empty());
+
+        // one seek for the first read, after that the tracked position is used
+        assert_eq!(server.request_count(afc_opcode_t::FILE_SEEK), 1);
+        // 768 reads of 4 KiB without read-ahead, 64 KiB reads with it
+        assert!(server.request_count(afc_opcode_t::READ) <= 3 * 16 + 2);
+    }
+
+    #[test]
+    fn random_reads_fetch_only_what_is_asked() {
+        crate::VERBOSE.get_or_init(|| false);
+        let (server, client) = MockServer::start();
+        std::fs::write(server.root().join("db"), vec![7u8; 2 * MIB]).unwrap();
+        let handle = open(&client, "/db", OpenMode::ReadOnly);
+
+        let mut file = OpenFile::new(MIB, MIB, false);
+        for offset in [MIB as u64, 4096, MIB as u64 + 8192] {
+            assert_eq!(
+                file.read(&client, handle, offset, 4096).unwrap(),
+                [7u8; 4096]
+            );
+        }
+        assert_eq!(server.request_count(afc_opcode_t::READ), 3);
+    }
+
+    #[test]
+    fn size_counts_queued_writes_and_set_size_sends_them_first() {
+        crate::VERBOSE.get_or_init(|| false);
+        let (server, client) = MockServer::start();
+        std::fs::write(server.root().join("doc"), b"0123456789").unwrap();
+        let handle = open(&client, "/doc", OpenMode::ReadWrite);
+
+        let mut file = OpenFile::new(MIB, MIB, false);
+        assert_eq!(file.size(&client, handle).unwrap(), 10);
+        file.write(&client, handle, 10, b"abcdef").unwrap();
+        assert_eq!(file.size(&client, handle).unwrap(), 16);
+
+        file.set_size(&client, handle, 12).unwrap();
+        assert_eq!(
+            std::fs::read(server.root().join("doc")).unwrap(),
+            b"0123456789ab"
+        );
+        assert_eq!(file.size(&client, handle).unwrap(), 12);
+        assert_eq!(file.read(&client, handle, 8, 10).unwrap(), b"89ab");
+    }
+
+    #[test]
+    fn consecutive_writes_are_coalesced_until_flush() {
+        crate::VERBOSE.get_or_init(|| false);
+        let (server, client) = MockServer::start();
+        let handle = open(&client, "/upload", OpenMode::ReadWrite);
+
+        let mut file = OpenFile::new(MIB, MIB, false);
+        for i in 0..64u64 {
+            file.write(&client, handle, i * 4096, &[i as u8; 4096])
+                .unwrap();
+        }
+        assert_eq!(server.request_count(afc_opcode_t::WRITE), 0);
+        file.flush(&client, handle).unwrap();
+        assert_eq!(server.request_count(afc_opcode_t::WRITE), 1);
+
+        // a write elsewhere sends what was queued first
+        file.write(&client, handle, 0, b"head").unwrap();
+        file.write(&client, handle, 100, b"gap").unwrap();
+        assert_eq!(server.request_count(afc_opcode_t::WRITE), 2);
+        // reading sees the queued write
+        assert_eq!(file.read(&client, handle, 100, 3).unwrap(), b"gap");
+
+        let on_disk = std::fs::read(server.root().join("upload")).unwrap();
+        assert_eq!(on_disk.len(), 64 * 4096);
+        assert_eq!(&on_disk[..4], b"head");
+        assert_eq!(&on_disk[100..103], b"gap");
+        assert_eq!(on_disk[4096 * 63], 63);
+    }
+
+    #[test]
+    fn a_lock_not_restored_after_reconnecting_is_not_held() {
+        crate::VERBOSE.get_or_init(|| false);
+        let (server, client) = MockServer::start();
+        let client = client.reconnect_with(server.connector(), Duration::from_secs(5));
+        let handle = open(&client, "/locked", OpenMode::ReadWrite);
+        let mut file = OpenFile::new(0, 0, false);
+
+        file.lock(&client, handle, LockOp::Exclusive).unwrap();
+        assert_eq!(file.held_lock(&client, handle), Some(LockOp::Exclusive));
+
+        // the file is reopened on the new connection, its lock cannot be taken back
+        server.unsupported(afc_opcode_t::FILE_LOCK);
+        server.inject(Fault::DropConnection);
+        assert_eq!(file.size(&client, handle), Ok(0));
+        assert_eq!(file.held_lock(&client, handle), None);
+    }
+}
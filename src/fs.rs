@@ -0,0 +1,383 @@
+use crate::{
+    afc::{extract_list, to_map, AfcError, AfcResponse, Client, OpenMode},
+    afc_link_type_t_AFC_HARDLINK, afc_link_type_t_AFC_SYMLINK,
+};
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Kind of a file, from the `st_ifmt` the device reports
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    BlockDevice,
+    CharDevice,
+    Fifo,
+    Socket,
+    Unknown,
+}
+
+impl FileType {
+    fn parse(ifmt: &str) -> Self {
+        match ifmt {
+            "S_IFREG" => FileType::File,
+            "S_IFDIR" => FileType::Directory,
+            "S_IFLNK" => FileType::Symlink,
+            "S_IFBLK" => FileType::BlockDevice,
+            "S_IFCHR" => FileType::CharDevice,
+            "S_IFIFO" => FileType::Fifo,
+            "S_IFSOCK" => FileType::Socket,
+            _ => FileType::Unknown,
+        }
+    }
+}
+
+/// Attributes of a file as GET_FILE_INFO reports them; missing ones are zero
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileInfo {
+    pub file_type: FileType,
+    pub size: u64,
+    pub blocks: u64,
+    pub nlink: u64,
+    pub modified: SystemTime,
+    pub created: SystemTime,
+    /// Where a symlink points, as it was created
+    pub link_target: Option<String>,
+}
+
+impl FileInfo {
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type == FileType::File
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type == FileType::Symlink
+    }
+
+    fn parse(info: &HashMap<String, String>) -> Self {
+        let number = |key: &str| -> u64 {
+            info.get(key)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0)
+        };
+        Self {
+            file_type: info
+                .get("st_ifmt")
+                .map_or(FileType::Unknown, |ifmt| FileType::parse(ifmt)),
+            size: number("st_size"),
+            blocks: number("st_blocks"),
+            nlink: number("st_nlink"),
+            modified: UNIX_EPOCH + Duration::from_nanos(number("st_mtime")),
+            created: UNIX_EPOCH + Duration::from_nanos(number("st_birthtime")),
+            link_target: info.get("LinkTarget").cloned(),
+        }
+    }
+}
+
+impl FileInfo {
+    /// An empty answer means the file is gone
+    fn from_response(info: AfcResponse) -> Result<Self, AfcError> {
+        match extract_list(info.into_result()?) {
+            Some(list) if list.is_empty() => Err(AfcError::ObjectNotFound),
+            Some(list) => Ok(FileInfo::parse(&to_map(list))),
+            None => Err(AfcError::IoError),
+        }
+    }
+}
+
+/// Storage of the device as GET_DEVICE_INFO reports it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub model: String,
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    pub block_size: u64,
+}
+
+impl DeviceInfo {
+    fn from_response(info: AfcResponse) -> Result<Self, AfcError> {
+        let info = to_map(extract_list(info.into_result()?).ok_or(AfcError::IoError)?);
+        let number = |key: &str| -> u64 {
+            info.get(key)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0)
+        };
+        Ok(Self {
+            model: info.get("Model").cloned().unwrap_or_default(),
+            total_bytes: number("FSTotalBytes"),
+            free_bytes: number("FSFreeBytes"),
+            block_size: number("FSBlockSize"),
+        })
+    }
+}
+
+/// An entry of a directory listed with `Client::read_dir`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub path: PathBuf,
+    pub info: FileInfo,
+}
+
+/// `path` as the device expects it, separated by `/` whatever the host uses
+fn device_path(path: &Path) -> Result<CString, AfcError> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::RootDir => parts.push(""),
+            Component::CurDir => parts.push("."),
+            Component::ParentDir => parts.push(".."),
+            Component::Normal(name) => parts.push(name.to_str().ok_or(AfcError::InvalidArg)?),
+            Component::Prefix(_) => return Err(AfcError::InvalidArg),
+        }
+    }
+    let path = match parts.as_slice() {
+        [""] => "/".to_string(),
+        parts => parts.join("/"),
+    };
+    CString::new(path).map_err(|_| AfcError::InvalidArg)
+}
+
+fn done(info: AfcResponse) -> Result<(), AfcError> {
+    info.into_result().map(drop)
+}
+
+impl Client {
+    pub fn stat(&self, path: impl AsRef<Path>) -> Result<FileInfo, AfcError> {
+        let path = device_path(path.as_ref())?;
+        FileInfo::from_response(self.get_file_info(path.as_ptr()))
+    }
+
+    /// Attributes of every path, fetched in one pipelined batch
+    pub fn stat_many<P: AsRef<Path>>(&self, paths: &[P]) -> Vec<Result<FileInfo, AfcError>> {
+        let paths: Vec<_> = paths
+            .iter()
+            .map(|path| device_path(path.as_ref()))
+            .collect();
+        let valid: Vec<CString> = paths.iter().flatten().cloned().collect();
+        let mut infos = self.get_file_info_many(&valid).into_iter();
+        paths
+            .into_iter()
+            .map(|path| path.and_then(|_| FileInfo::from_response(infos.next().unwrap())))
+            .collect()
+    }
+
+    /// Names in the directory `path`, without `.` and `..`, in the device's order
+    pub fn list_dir(&self, path: impl AsRef<Path>) -> Result<Vec<String>, AfcError> {
+        let path = device_path(path.as_ref())?;
+        let list = self.read_directory(path.as_ptr()).into_result()?;
+        Ok(extract_list(list)
+            .ok_or(AfcError::IoError)?
+            .into_iter()
+            .filter(|name| name != "." && name != "..")
+            .collect())
+    }
+
+    /// Entries of the directory `path` in name order, with their attributes fetched in
+    /// one pipelined batch. Entries removed before that are left out.
+    pub fn read_dir(&self, path: impl AsRef<Path>) -> Result<Vec<DirEntry>, AfcError> {
+        let path = path.as_ref();
+        let mut names = self.list_dir(path)?;
+        names.sort();
+        let paths: Vec<PathBuf> = names.iter().map(|name| path.join(name)).collect();
+        let infos = self.stat_many(&paths);
+        Ok(names
+            .into_iter()
+            .zip(paths)
+            .zip(infos)
+            .filter_map(|((name, path), info)| {
+                Some(DirEntry {
+                    name,
+                    path,
+                    info: info.ok()?,
+                })
+            })
+            .collect())
+    }
+
+    pub fn device_info(&self) -> Result<DeviceInfo, AfcError> {
+        DeviceInfo::from_response(self.get_device_info())
+    }
+
+    /// Removes a file or an empty directory
+    pub fn remove(&self, path: impl AsRef<Path>) -> Result<(), AfcError> {
+        let path = device_path(path.as_ref())?;
+        done(self.remove_path(path.as_ptr()))
+    }
+
+    /// Removes a file or a directory with everything in it, which older devices do
+    /// not support
+    pub fn remove_all(&self, path: impl AsRef<Path>) -> Result<(), AfcError> {
+        let path = device_path(path.as_ref())?;
+        done(self.remove_path_and_contents(path.as_ptr()))
+    }
+
+    /// Creates a directory, along with missing parents
+    pub fn create_dir(&self, path: impl AsRef<Path>) -> Result<(), AfcError> {
+        let path = device_path(path.as_ref())?;
+        done(self.make_directory(path.as_ptr()))
+    }
+
+    /// Renames a file or directory. The device does not replace an existing `to`, it
+    /// answers `AfcError::ObjectExists` instead.
+    pub fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), AfcError> {
+        let from = device_path(from.as_ref())?;
+        let to = device_path(to.as_ref())?;
+        done(self.rename_path(from.as_ptr(), to.as_ptr()))
+    }
+
+    /// Truncates or extends the file at `path` to `size` bytes
+    pub fn set_len(&self, path: impl AsRef<Path>, size: u64) -> Result<(), AfcError> {
+        let path = device_path(path.as_ref())?;
+        done(self.truncate(path.as_ptr(), size))
+    }
+
+    pub fn set_modified(&self, path: impl AsRef<Path>, time: SystemTime) -> Result<(), AfcError> {
+        let path = device_path(path.as_ref())?;
+        let nanos = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        done(self.set_file_time(path.as_ptr(), nanos))
+    }
+
+    /// Creates a symlink at `link` pointing to `target`, which is stored as given
+    pub fn symlink(&self, target: &str, link: impl AsRef<Path>) -> Result<(), AfcError> {
+        let target = CString::new(target).map_err(|_| AfcError::InvalidArg)?;
+        let link = device_path(link.as_ref())?;
+        done(self.make_link(afc_link_type_t_AFC_SYMLINK, target.as_ptr(), link.as_ptr()))
+    }
+
+    pub fn hard_link(
+        &self,
+        target: impl AsRef<Path>,
+        link: impl AsRef<Path>,
+    ) -> Result<(), AfcError> {
+        let target = device_path(target.as_ref())?;
+        let link = device_path(link.as_ref())?;
+        done(self.make_link(afc_link_type_t_AFC_HARDLINK, target.as_ptr(), link.as_ptr()))
+    }
+
+    /// Every attribute GET_FILE_INFO reports for `path`, in the device's order
+    pub fn attributes(&self, path: impl AsRef<Path>) -> Result<Vec<(String, String)>, AfcError> {
+        let path = device_path(path.as_ref())?;
+        let list = extract_list(self.get_file_info(path.as_ptr()).into_result()?);
+        Ok(list
+            .unwrap_or_default()
+            .chunks_exact(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect())
+    }
+
+    /// Opens the file at `path`, returning the handle the `file_*` requests take
+    pub fn open_file(&self, path: impl AsRef<Path>, mode: OpenMode) -> Result<u64, AfcError> {
+        let path = device_path(path.as_ref())?;
+        self.file_open(path.as_ptr(), mode as u64).into_num()
+    }
+
+    /// Where the symlink at `path` points
+    pub fn read_link(&self, path: impl AsRef<Path>) -> Result<String, AfcError> {
+        self.stat(path)?.link_target.ok_or(AfcError::InvalidArg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+
+    #[test]
+    fn paths_are_sent_with_slashes() {
+        let path = |path: &str| device_path(Path::new(path)).unwrap();
+        assert_eq!(path("/"), c"/".to_owned());
+        assert_eq!(path("/DCIM/./100APPLE/"), c"/DCIM/100APPLE".to_owned());
+        assert_eq!(path("Documents/a b"), c"Documents/a b".to_owned());
+        assert_eq!(
+            device_path(&Path::new("/DCIM").join("IMG_1.JPG")).unwrap(),
+            c"/DCIM/IMG_1.JPG".to_owned()
+        );
+        assert_eq!(device_path(Path::new("/a\0b")), Err(AfcError::InvalidArg));
+    }
+
+    #[test]
+    fn typed_requests_against_the_mock() {
+        crate::VERBOSE.get_or_init(|| false);
+        let (server, client) = MockServer::start();
+        client.create_dir("/DCIM/100APPLE").unwrap();
+        std::fs::write(server.root().join("DCIM/100APPLE/IMG_1.JPG"), b"jpeg").unwrap();
+        std::fs::write(server.root().join("DCIM/a.txt"), b"").unwrap();
+
+        let info = client.stat("/DCIM/100APPLE/IMG_1.JPG").unwrap();
+        assert!(info.is_file());
+        assert_eq!(info.size, 4);
+        assert!(info.modified > UNIX_EPOCH);
+        assert_eq!(client.stat("/missing"), Err(AfcError::ObjectNotFound));
+
+        let entries = client.read_dir("/DCIM").unwrap();
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["100APPLE", "a.txt"]);
+        assert!(entries[0].info.is_dir());
+        assert_eq!(entries[0].path, Path::new("/DCIM/100APPLE"));
+
+        client.rename("/DCIM/a.txt", "/DCIM/b.txt").unwrap();
+        assert_eq!(
+            client.rename("/DCIM/b.txt", "/DCIM/100APPLE/IMG_1.JPG"),
+            Err(AfcError::ObjectExists)
+        );
+        client.set_len("/DCIM/b.txt", 10).unwrap();
+        assert_eq!(client.stat("/DCIM/b.txt").unwrap().size, 10);
+        assert_eq!(client.remove("/DCIM"), Err(AfcError::DirNotEmpty));
+        client.remove("/DCIM/b.txt").unwrap();
+        assert_eq!(client.list_dir("/DCIM").unwrap(), ["100APPLE"]);
+    }
+
+    #[test]
+    fn links_against_the_mock() {
+        crate::VERBOSE.get_or_init(|| false);
+        let (server, client) = MockServer::start();
+        std::fs::write(server.root().join("a.txt"), b"text").unwrap();
+
+        client.hard_link("/a.txt", "/hard").unwrap();
+        assert_eq!(client.stat("/hard").unwrap().nlink, 2);
+        std::fs::write(server.root().join("a.txt"), b"changed").unwrap();
+        assert_eq!(
+            std::fs::read(server.root().join("hard")).unwrap(),
+            b"changed"
+        );
+        assert_eq!(
+            client.hard_link("/a.txt", "/hard"),
+            Err(AfcError::ObjectExists)
+        );
+
+        #[cfg(unix)]
+        {
+            client.symlink("a.txt", "/link").unwrap();
+            let info = client.stat("/link").unwrap();
+            assert!(info.is_symlink());
+            assert_eq!(client.read_link("/link").unwrap(), "a.txt");
+            assert_eq!(client.read_link("/a.txt"), Err(AfcError::InvalidArg));
+        }
+    }
+
+    #[test]
+    fn remove_all_takes_the_contents_along() {
+        crate::VERBOSE.get_or_init(|| false);
+        let (server, client) = MockServer::start();
+        client.create_dir("/a/b/c").unwrap();
+        std::fs::write(server.root().join("a/b/f"), b"x").unwrap();
+        std::fs::write(server.root().join("a/g"), b"x").unwrap();
+
+        assert_eq!(client.remove("/a"), Err(AfcError::DirNotEmpty));
+        client.remove_all("/a").unwrap();
+        assert!(!server.root().join("a").exists());
+        assert_eq!(client.remove_all("/a"), Err(AfcError::ObjectNotFound));
+    }
+}
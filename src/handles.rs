@@ -0,0 +1,209 @@
+#![allow(non_upper_case_globals)]
+use crate::{
+    afc::{AfcRequest, AfcResponse, Response},
+    afc_file_mode_t_AFC_FOPEN_APPEND, afc_file_mode_t_AFC_FOPEN_RDAPPEND,
+    afc_file_mode_t_AFC_FOPEN_RW, afc_file_mode_t_AFC_FOPEN_WR, afc_file_mode_t_AFC_FOPEN_WRONLY,
+    afc_lock_op_t_AFC_LOCK_UN,
+    protocol::afc_opcode_t,
+    SEEK_SET,
+};
+use std::collections::HashMap;
+
+/// Sent in place of a handle the device does not know, so the request fails there
+const NO_HANDLE: u64 = u64::MAX;
+
+/// File handles as `Client` hands them out, stable across reconnects.
+///
+/// The device numbers handles per connection, so a reopened file gets a new one.
+/// Requests carry the handle the caller was given and are rewritten to the device's
+/// current handle when sent. The file pointer and lock are followed from the replies
+/// so a reopened file can be put back where it was.
+pub(crate) struct Handles {
+    next: u64,
+    files: HashMap<u64, OpenHandle>,
+}
+
+struct OpenHandle {
+    path: Vec<u8>,
+    mode: u64,
+    device: u64,
+    position: Option<u64>,
+    lock: Option<u64>,
+}
+
+impl OpenHandle {
+    fn append(&self) -> bool {
+        self.mode == afc_file_mode_t_AFC_FOPEN_APPEND
+            || self.mode == afc_file_mode_t_AFC_FOPEN_RDAPPEND
+    }
+}
+
+/// Operations whose payload starts with a file handle
+pub(crate) fn carries_handle(operation: afc_opcode_t) -> bool {
+    matches!(
+        operation,
+        afc_opcode_t::READ
+            | afc_opcode_t::WRITE
+            | afc_opcode_t::FILE_SEEK
+            | afc_opcode_t::FILE_TELL
+            | afc_opcode_t::FILE_CLOSE
+            | afc_opcode_t::FILE_SET_SIZE
+            | afc_opcode_t::FILE_LOCK
+    )
+}
+
+fn u64_at(payload: &[u8], offset: usize) -> Option<u64> {
+    payload
+        .get(offset..offset + 8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+impl Handles {
+    pub fn new() -> Self {
+        Self {
+            next: 1,
+            files: HashMap::new(),
+        }
+    }
+
+    /// Handle the device knows for the file `request` is about
+    pub fn device_handle(&self, request: &AfcRequest) -> u64 {
+        u64_at(&request.payload, 0)
+            .and_then(|handle| self.files.get(&handle))
+            .map_or(NO_HANDLE, |file| file.device)
+    }
+
+    /// Lock the file is known to hold on the device, as AFC numbers lock operations
+    pub fn held_lock(&self, handle: u64) -> Option<u64> {
+        self.files.get(&handle).and_then(|file| file.lock)
+    }
+
+    /// Follows the effect of an answered request, and hands out the caller's handle
+    /// in place of the device's for a file just opened
+    pub fn track(&mut self, request: &AfcRequest, response: &mut AfcResponse) {
+        let handle = u64_at(&request.payload, 0).unwrap_or(NO_HANDLE);
+        let success = response.status.is_ok();
+
+        if request.operation == afc_opcode_t::FILE_OPEN {
+            if let (true, Response::Number(device)) = (success, &response.data) {
+                let file = OpenHandle {
+                    path: request.payload[8..].to_vec(),
+                    mode: handle,
+                    device: *device,
+                    position: Some(0),
+                    lock: None,
+                };
+                let handle = self.next;
+                self.next += 1;
+                self.files.insert(handle, file);
+                response.data = Response::Number(handle);
+            }
+            return;
+        }
+        if request.operation == afc_opcode_t::FILE_CLOSE {
+            self.files.remove(&handle);
+            return;
+        }
+
+        let Some(file) = self.files.get_mut(&handle) else {
+            return;
+        };
+        if !success {
+            // a failed transfer may have moved the pointer by any amount
+            if matches!(
+                request.operation,
+                afc_opcode_t::READ | afc_opcode_t::WRITE | afc_opcode_t::FILE_SEEK
+            ) {
+                file.position = None;
+            }
+            return;
+        }
+        match (request.operation, &response.data) {
+            (afc_opcode_t::FILE_SEEK, _) => {
+                let whence = u64_at(&request.payload, 8);
+                let offset = u64_at(&request.payload, 16);
+                file.position = offset.filter(|_| whence == Some(SEEK_SET as u64));
+            }
+            (afc_opcode_t::FILE_TELL, Response::Number(position)) => {
+                file.position = Some(*position)
+            }
+            (afc_opcode_t::READ, Response::Byte(data)) => {
+                file.position = file.position.map(|position| position + data.len() as u64)
+            }
+            (afc_opcode_t::WRITE, _) if file.append() => file.position = None,
+            (afc_opcode_t::WRITE, _) => {
+                let written = request.payload.len().saturating_sub(8) as u64;
+                file.position = file.position.map(|position| position + written)
+            }
+            (afc_opcode_t::FILE_LOCK, _) => {
+                let operation = u64_at(&request.payload, 8);
+                file.lock = operation.filter(|op| *op != afc_lock_op_t_AFC_LOCK_UN as u64);
+            }
+            _ => {}
+        }
+    }
+
+    /// FILE_OPEN requests opening every file again, with the handle each one is for.
+    /// Files first opened with truncation are reopened without it.
+    pub fn reopen(&self) -> Vec<(u64, AfcRequest)> {
+        self.files
+            .iter()
+            .map(|(handle, file)| {
+                let mode = match file.mode {
+                    afc_file_mode_t_AFC_FOPEN_WRONLY | afc_file_mode_t_AFC_FOPEN_WR => {
+                        afc_file_mode_t_AFC_FOPEN_RW
+                    }
+                    mode => mode,
+                };
+                let mut payload = mode.to_le_bytes().to_vec();
+                payload.extend_from_slice(&file.path);
+                (*handle, AfcRequest::new(afc_opcode_t::FILE_OPEN, payload))
+            })
+            .collect()
+    }
+
+    /// Records the device handle of a reopened file, `None` if it could not be reopened
+    pub fn rebind(&mut self, handle: u64, device: Option<u64>) {
+        if let Some(file) = self.files.get_mut(&handle) {
+            file.device = device.unwrap_or(NO_HANDLE);
+            if device.is_none() {
+                file.position = None;
+                file.lock = None;
+            }
+        }
+    }
+
+    /// Requests putting the pointer and lock of every reopened file back, addressed
+    /// with the device's handles
+    pub fn restore(&self) -> Vec<(u64, AfcRequest)> {
+        let mut requests = Vec::new();
+        for (handle, file) in &self.files {
+            if file.device == NO_HANDLE {
+                continue;
+            }
+            if let Some(position) = file.position.filter(|_| !file.append()) {
+                let mut payload = file.device.to_le_bytes().to_vec();
+                payload.extend_from_slice(&(SEEK_SET as u64).to_le_bytes());
+                payload.extend_from_slice(&position.to_le_bytes());
+                requests.push((*handle, AfcRequest::new(afc_opcode_t::FILE_SEEK, payload)));
+            }
+            if let Some(lock) = file.lock {
+                let mut payload = file.device.to_le_bytes().to_vec();
+                payload.extend_from_slice(&lock.to_le_bytes());
+                requests.push((*handle, AfcRequest::new(afc_opcode_t::FILE_LOCK, payload)));
+            }
+        }
+        requests
+    }
+
+    /// Forgets what a failed restore request was meant to bring back
+    pub fn lost(&mut self, handle: u64, operation: afc_opcode_t) {
+        if let Some(file) = self.files.get_mut(&handle) {
+            match operation {
+                afc_opcode_t::FILE_SEEK => file.position = None,
+                afc_opcode_t::FILE_LOCK => file.lock = None,
+                _ => {}
+            }
+        }
+    }
+}
@@ -1,13 +1,10 @@
-use crate::{
-    afc::{Client, IDeviceConnection},
-    idevice_connection_receive_timeout, idevice_error_t_IDEVICE_E_SUCCESS,
-};
+use crate::{afc::Client, idevice_error_t_IDEVICE_E_SUCCESS, transport::Transport};
 use byteorder::{BigEndian, ByteOrder};
 use plist::Value;
 
 impl Client {
     pub fn start_house_arrest(&self, app_id: String) -> i32 {
-        let socket = self.socket.lock().unwrap();
+        let mut socket = self.socket.lock().unwrap();
 
         let mut command_plist = plist::Dictionary::new();
 
@@ -19,16 +16,16 @@ impl Client {
         let len = payload.len() as u32;
         let prefix = len.to_be_bytes();
 
-        let (res, _) = self.send_packet(&socket, prefix.to_vec(), prefix.len() as _);
+        let (res, _) = self.send_packet(socket.as_mut(), prefix.to_vec(), prefix.len() as _);
         if res != idevice_error_t_IDEVICE_E_SUCCESS {
             return -1;
         }
-        let (res, _) = self.send_packet(&socket, payload, len);
+        let (res, _) = self.send_packet(socket.as_mut(), payload, len);
         if res != idevice_error_t_IDEVICE_E_SUCCESS {
             return -1;
         }
 
-        if let Ok(dict) = receive_plist(&socket) {
+        if let Ok(dict) = receive_plist(socket.as_mut()) {
             if let Some(status) = dict.get("Status") {
                 if status.as_string().unwrap() == "Complete" {
                     return 0;
@@ -40,58 +37,41 @@ impl Client {
     }
 }
 
-fn receive_plist(
-    connection: &std::sync::MutexGuard<IDeviceConnection>,
-) -> Result<plist::Dictionary, String> {
-    unsafe {
-        let mut pktlen = vec![0u8; size_of::<u32>()];
-        let mut recv_bytes = 0;
-        let res = idevice_connection_receive_timeout(
-            connection.connection(),
-            pktlen.as_mut_ptr() as *mut i8,
-            pktlen.len() as _,
-            &mut recv_bytes,
-            5000,
-        );
-        if res != 0 {
-            return Err(format!("initial read failed! status={:?}", res));
-        }
+fn receive_plist(connection: &mut dyn Transport) -> Result<plist::Dictionary, String> {
+    let mut pktlen = vec![0u8; size_of::<u32>()];
+    let (res, _) = connection.receive_timeout(&mut pktlen, 5000);
+    if res != idevice_error_t_IDEVICE_E_SUCCESS {
+        return Err(format!("initial read failed! status={:?}", res));
+    }
 
-        let mut curlen = 0;
-        let pktlen = BigEndian::read_u32(&pktlen);
+    let mut curlen = 0;
+    let pktlen = BigEndian::read_u32(&pktlen);
 
-        let mut content = Vec::new();
+    let mut content = Vec::new();
 
-        while curlen < pktlen {
-            let mut buf = vec![0u8; (pktlen - curlen) as usize];
-            let res = idevice_connection_receive_timeout(
-                connection.connection(),
-                buf.as_mut_ptr() as *mut i8,
-                buf.len() as _,
-                &mut recv_bytes,
-                5000,
-            );
-            if res != idevice_error_t_IDEVICE_E_SUCCESS {
-                return Err(format!("Read failed! status={:?}", res));
-            }
-            content.extend_from_slice(&buf[0..recv_bytes as usize]);
-            curlen += recv_bytes;
+    while curlen < pktlen {
+        let mut buf = vec![0u8; (pktlen - curlen) as usize];
+        let (res, recv_bytes) = connection.receive_timeout(&mut buf, 5000);
+        if res != idevice_error_t_IDEVICE_E_SUCCESS {
+            return Err(format!("Read failed! status={:?}", res));
         }
+        content.extend_from_slice(&buf[0..recv_bytes as usize]);
+        curlen += recv_bytes;
+    }
 
-        if curlen < pktlen {
-            return Err(format!(
-                "received incomplete packet ({:?} of {:?} bytes)",
-                curlen, pktlen
-            ));
-        }
+    if curlen < pktlen {
+        return Err(format!(
+            "received incomplete packet ({:?} of {:?} bytes)",
+            curlen, pktlen
+        ));
+    }
 
-        let reader = std::io::Cursor::new(content);
-        if let Ok(xml) = plist::Value::from_reader_xml(reader) {
-            if let Some(dict) = xml.into_dictionary() {
-                return Ok(dict);
-            }
+    let reader = std::io::Cursor::new(content);
+    if let Ok(xml) = plist::Value::from_reader_xml(reader) {
+        if let Some(dict) = xml.into_dictionary() {
+            return Ok(dict);
         }
-
-        Err("Received unexpected non-plist content".to_string())
     }
+
+    Err("Received unexpected non-plist content".to_string())
 }
@@ -1,10 +1,10 @@
-use crate::{afc::Client, idevice_connection_receive_timeout, idevice_error_t_IDEVICE_E_SUCCESS};
+use crate::{afc::Client, idevice_error_t_IDEVICE_E_SUCCESS};
 use plist::Value;
 use std::collections::HashMap;
 
 impl Client {
     pub fn list_apps(&self) -> Option<Vec<HashMap<String, String>>> {
-        let socket = self.socket.lock().unwrap();
+        let mut socket = self.socket.lock().unwrap();
         let mut num = self.packet_num.lock().unwrap();
         *num += 1;
 
@@ -30,41 +30,24 @@ impl Client {
         let len = payload.len() as u32;
         let prefix = len.to_be_bytes();
 
-        let (res, _) = self.send_packet(&socket, prefix.to_vec(), prefix.len() as _);
+        let (res, _) = self.send_packet(socket.as_mut(), prefix.to_vec(), prefix.len() as _);
         if res != idevice_error_t_IDEVICE_E_SUCCESS {
             return None;
         }
-        let (res, _) = self.send_packet(&socket, payload, len);
+        let (res, _) = self.send_packet(socket.as_mut(), payload, len);
         if res != idevice_error_t_IDEVICE_E_SUCCESS {
             return None;
         }
 
         let mut len_buf = [0u8; 4];
-        let mut recv_bytes = 0;
-        unsafe {
-            idevice_connection_receive_timeout(
-                socket.connection(),
-                len_buf.as_mut_ptr() as *mut i8,
-                4,
-                &mut recv_bytes,
-                5000,
-            )
-        };
+        socket.receive_timeout(&mut len_buf, 5000);
 
         let mut reply_len = u32::from_be_bytes(len_buf) as usize;
         let mut results: Vec<HashMap<String, String>> = Vec::new();
         loop {
             let mut reply_buf = vec![0u8; reply_len];
-            let res = unsafe {
-                idevice_connection_receive_timeout(
-                    socket.connection(),
-                    reply_buf.as_mut_ptr() as *mut i8,
-                    reply_len as _,
-                    &mut recv_bytes,
-                    5000,
-                )
-            };
-            if res != 0 {
+            let (res, _) = socket.receive_timeout(&mut reply_buf, 5000);
+            if res != idevice_error_t_IDEVICE_E_SUCCESS {
                 break;
             }
 
@@ -93,16 +76,7 @@ impl Client {
             }
 
             let mut len_buf = [0u8; 4];
-
-            unsafe {
-                idevice_connection_receive_timeout(
-                    socket.connection(),
-                    len_buf.as_mut_ptr() as *mut i8,
-                    4,
-                    &mut recv_bytes,
-                    5000,
-                )
-            };
+            socket.receive_timeout(&mut len_buf, 5000);
             reply_len = u32::from_be_bytes(len_buf) as usize;
         }
 
@@ -110,7 +84,7 @@ impl Client {
     }
 }
 
-pub(crate) fn print_app(sharing_only: bool, info: Vec<HashMap<String, String>>) {
+pub fn print_app(sharing_only: bool, info: Vec<HashMap<String, String>>) {
     for app in info {
         if sharing_only {
             if app.contains_key("UIFileSharingEnabled") {
@@ -0,0 +1,38 @@
+//! Client for the AFC service of iOS devices, spoken over libimobiledevice.
+//!
+//! `Client` offers path-based methods and operations on open handles, all
+//! returning typed results. `AfcFile` reads and writes device files
+//! through `std::io`; the `ifuse` binary mounts a device with it.
+mod afc;
+mod bindings;
+pub mod errno;
+mod file;
+mod fs;
+mod handles;
+mod housearrest;
+pub mod instproxy;
+#[cfg(test)]
+mod mock;
+mod protocol;
+mod transport;
+mod walk;
+pub use afc::{AfcError, Client, Connector, LockOp, OpenMode};
+pub(crate) use bindings::*;
+pub use file::AfcFile;
+pub use fs::{DeviceInfo, DirEntry, FileInfo, FileType};
+use std::sync::OnceLock;
+pub use transport::{MemoryTransport, TcpTransport, Transport};
+pub use walk::{Symlinks, Walk, WalkError};
+
+/// Whether requests are logged to stdout, off unless set
+pub static VERBOSE: OnceLock<bool> = OnceLock::new();
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        if $crate::VERBOSE.get().copied().unwrap_or(false) {
+            println!($($arg)*);
+        }
+    }};
+}
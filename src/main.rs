@@ -1,18 +1,38 @@
 #![allow(unused_variables)]
-mod afc;
 mod bindings;
-mod housearrest;
-mod instproxy;
-use crate::instproxy::print_app;
-use afc::*;
-pub(crate) use bindings::*;
-use clap::{arg, Args, Parser};
-use std::sync::OnceLock;
+mod cache;
+mod commands;
+mod device;
+mod files;
+#[cfg(test)]
+mod mock;
+mod pool;
+#[cfg(test)]
+mod protocol;
+mod shell;
+mod watch;
+use bindings::*;
+use cache::Cache;
+use clap::{error::ErrorKind, Args, CommandFactory, Parser, Subcommand};
+use files::OpenFiles;
+use ifuse::{
+    debug, errno::*, instproxy::print_app, AfcError, Client, FileInfo, FileType, LockOp, OpenMode,
+    Transport, VERBOSE,
+};
+#[cfg(test)]
+use ifuse::{Connector, MemoryTransport, TcpTransport};
+use pool::Pool;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex, OnceLock,
+};
 use std::{
     ffi::{CStr, CString},
     mem::MaybeUninit,
     os::raw::c_void,
+    path::PathBuf,
     str,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 const AFC_SERVICE_NAME: &str = "com.apple.afc";
@@ -26,20 +46,205 @@ struct Cli {
     #[arg(group = "mount")]
     mount_point: Option<String>,
 
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Use house_arrest service.
-    #[arg(short, long, requires = "mount", value_name = "appid")]
+    #[arg(short, long, global = true, value_name = "appid")]
     documents: Option<String>,
 
     #[command(flatten)]
     vers: Option<ListApps>,
 
-    #[arg(short, long)]
+    /// Print attached devices
+    #[arg(long, conflicts_with = "mount")]
+    list_devices: bool,
+
+    /// Keep running and mount each device as it is plugged in; the mount point may
+    /// contain {name} and {udid}
+    #[arg(long, requires = "mount")]
+    watch: bool,
+
+    /// Use the device with this UDID
+    #[arg(short, long, global = true, value_name = "UDID")]
+    udid: Option<String>,
+
+    /// Only look for devices connected over USB
+    #[arg(long, global = true, conflicts_with = "network")]
+    usb: bool,
+
+    /// Only look for devices connected over the network
+    #[arg(short, long, global = true)]
+    network: bool,
+
+    /// Remove directories together with their contents
+    #[arg(long, requires = "mount")]
+    recursive_delete: bool,
+
+    /// Number of AFC connections opened for concurrent requests
+    #[arg(long, value_name = "N", default_value_t = 4, requires = "mount")]
+    connections: usize,
+
+    /// MiB read ahead once a file is read sequentially, 0 disables read-ahead
+    #[arg(long, value_name = "MIB", default_value_t = 1, requires = "mount")]
+    read_ahead: usize,
+
+    /// MiB of consecutive writes sent as one request, 0 writes through
+    #[arg(long, value_name = "MIB", default_value_t = 1, requires = "mount")]
+    write_buffer: usize,
+
+    /// Refuse every request that would modify the device
+    #[arg(long, requires = "mount")]
+    read_only: bool,
+
+    /// Keep locks local to this computer instead of taking them on the device
+    #[arg(long, requires = "mount")]
+    no_locking: bool,
+
+    /// Seconds to keep trying to reach the device again after the connection is lost, 0 disables reconnecting
+    #[arg(long, value_name = "SECONDS", default_value_t = 30, requires = "mount")]
+    reconnect_timeout: u64,
+
+    /// Fetch attributes of all entries while listing a directory
+    #[arg(long, requires = "mount")]
+    prefetch_attrs: bool,
+
+    /// Seconds attributes and directory listings are cached, 0 disables the cache
+    #[arg(long, value_name = "SECONDS", default_value_t = 5, requires = "mount")]
+    cache_ttl: u64,
+
+    /// Maximum number of cached attributes and of cached directory listings
+    #[arg(
+        long,
+        value_name = "ENTRIES",
+        default_value_t = 10000,
+        requires = "mount"
+    )]
+    cache_size: usize,
+
+    #[arg(short, long, global = true)]
     verbose: bool,
 
-    #[arg(long, action = clap::ArgAction::Help)]
+    #[arg(long, global = true, action = clap::ArgAction::Help)]
     help: Option<bool>,
 }
 
+impl Cli {
+    /// Mount options to pass on to the mounts started by `--watch`
+    fn mount_args(&self) -> Vec<String> {
+        let mut args = vec![
+            format!("--connections={}", self.connections),
+            format!("--read-ahead={}", self.read_ahead),
+            format!("--write-buffer={}", self.write_buffer),
+            format!("--reconnect-timeout={}", self.reconnect_timeout),
+            format!("--cache-ttl={}", self.cache_ttl),
+            format!("--cache-size={}", self.cache_size),
+        ];
+        if let Some(app_id) = &self.documents {
+            args.push(format!("--documents={}", app_id));
+        }
+        for (set, flag) in [
+            (self.recursive_delete, "--recursive-delete"),
+            (self.read_only, "--read-only"),
+            (self.no_locking, "--no-locking"),
+            (self.prefetch_attrs, "--prefetch-attrs"),
+            (self.verbose, "--verbose"),
+        ] {
+            if set {
+                args.push(flag.to_string());
+            }
+        }
+        args
+    }
+}
+
+// commands run against the device without mounting it
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List a directory
+    Ls {
+        #[arg(default_value = "/")]
+        path: String,
+        /// Show type, size and modification time
+        #[arg(short, long)]
+        long: bool,
+        /// List subdirectories too
+        #[arg(short = 'R', long)]
+        recursive: bool,
+    },
+    /// Print the attributes of a file
+    Stat { path: String },
+    /// Print the contents of a file
+    Cat { path: String },
+    /// Copy a file from the device
+    Pull {
+        path: String,
+        #[arg(default_value = ".")]
+        local: PathBuf,
+        /// Copy directories with their contents
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// Copy a file to the device
+    Push {
+        local: PathBuf,
+        #[arg(default_value = "/")]
+        path: String,
+        /// Copy directories with their contents
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// Remove a file or an empty directory
+    Rm {
+        path: String,
+        /// Remove directories with their contents
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// Create a directory, along with missing parents
+    Mkdir { path: String },
+    /// Rename a file or directory
+    Mv { from: String, to: String },
+    /// Browse the device interactively
+    Shell,
+}
+
+impl Command {
+    fn run(self, client: &Client) -> Result<(), AfcError> {
+        match self {
+            Command::Ls {
+                path,
+                long,
+                recursive,
+            } => commands::ls(
+                client,
+                &path,
+                long,
+                recursive,
+                &mut std::io::stdout().lock(),
+            ),
+            Command::Stat { path } => {
+                commands::stat_path(client, &path, &mut std::io::stdout().lock())
+            }
+            Command::Cat { path } => commands::cat(client, &path, &mut std::io::stdout().lock()),
+            Command::Pull {
+                path,
+                local,
+                recursive,
+            } => commands::pull(client, &path, &local, recursive),
+            Command::Push {
+                local,
+                path,
+                recursive,
+            } => commands::push(client, &local, &path, recursive),
+            Command::Rm { path, recursive } => commands::rm(client, &path, recursive),
+            Command::Mkdir { path } => commands::mkdir(client, &path),
+            Command::Mv { from, to } => commands::mv(client, &from, &to),
+            Command::Shell => unreachable!("the shell opens its own connections"),
+        }
+    }
+}
+
 #[derive(Args, Debug)]
 #[group(required = false, multiple = false)]
 struct ListApps {
@@ -84,18 +289,21 @@ impl Device {
 }
 
 static DEVICE: OnceLock<Device> = OnceLock::new();
-static CLIENT: OnceLock<Client> = OnceLock::new();
+/// Devices looked up again by reconnecting connections, freed on unmount
+static RECONNECTED: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+static POOL: OnceLock<Pool> = OnceLock::new();
 static IN_HOUSE_ARREST: OnceLock<bool> = OnceLock::new();
-static VERBOSE: OnceLock<bool> = OnceLock::new();
-
-macro_rules! debug {
-    ($($arg:tt)*) => {{
-        if *crate::VERBOSE.get().unwrap() {
-            println!($($arg)*);
-        }
-    }};
+static RECURSIVE_DELETE: OnceLock<bool> = OnceLock::new();
+static CACHE: OnceLock<Cache> = OnceLock::new();
+static PREFETCH_ATTRS: OnceLock<bool> = OnceLock::new();
+static OPEN_FILES: OnceLock<OpenFiles> = OnceLock::new();
+static LOCKING: OnceLock<bool> = OnceLock::new();
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Whether the mount refuses changes
+fn read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
 }
-pub(crate) use debug;
 
 fn main() {
     let args = Cli::parse();
@@ -105,6 +313,29 @@ fn main() {
         return;
     }
 
+    if args.command.is_some() && args.mount_point.is_some() {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "a command cannot be used with a mount point",
+            )
+            .exit();
+    }
+    // a command that cannot reach the service fails like a request to it would
+    let exit_code = if args.command.is_some() {
+        AfcError::ServiceNotConnected as i32
+    } else {
+        0
+    };
+
+    if args.watch {
+        VERBOSE.get_or_init(|| args.verbose);
+        let lookup = device::lookup_options(args.usb, args.network);
+        let template = args.mount_point.clone().unwrap_or_default();
+        watch::run(&template, args.udid.as_deref(), lookup, args.mount_args());
+        return;
+    }
+
     let mount_point = args.mount_point.unwrap_or_default();
     let mp = mount_point.clone();
     ctrlc::set_handler(move || {
@@ -117,6 +348,19 @@ fn main() {
     .expect("Error setting Ctrl-C handler");
 
     VERBOSE.get_or_init(|| args.verbose);
+
+    let lookup = device::lookup_options(args.usb, args.network);
+    if args.list_devices {
+        device::print_devices(lookup);
+        return;
+    }
+
+    RECURSIVE_DELETE.get_or_init(|| args.recursive_delete);
+    PREFETCH_ATTRS.get_or_init(|| args.prefetch_attrs);
+    LOCKING.get_or_init(|| !args.no_locking);
+    READ_ONLY.store(args.read_only, Ordering::Relaxed);
+    OPEN_FILES.get_or_init(|| OpenFiles::new(args.read_ahead << 20, args.write_buffer << 20));
+    CACHE.get_or_init(|| Cache::new(Duration::from_secs(args.cache_ttl), args.cache_size));
     let list_apps = args.vers.is_some();
     let app_id = args.documents.unwrap_or_default();
     let mut opt = Vec::new();
@@ -138,20 +382,29 @@ fn main() {
     debug!("Finding device connected...");
     let mut device_info = MaybeUninit::<idevice_t>::zeroed();
     let device_info_ptr = device_info.as_mut_ptr();
+    let udid = args.udid.map(|udid| CString::new(udid).unwrap());
     let res = unsafe {
         idevice_new_with_options(
             device_info_ptr,
-            std::ptr::null(),
-            idevice_options_IDEVICE_LOOKUP_USBMUX | idevice_options_IDEVICE_LOOKUP_NETWORK,
+            udid.as_ref().map_or(std::ptr::null(), |udid| udid.as_ptr()),
+            lookup,
         )
     };
     if res != idevice_error_t_IDEVICE_E_SUCCESS {
         eprintln!("No device found:{:?}", res);
-        return;
+        std::process::exit(exit_code);
     }
 
     let device = unsafe { device_info.assume_init() };
 
+    if matches!(args.command, Some(Command::Shell)) {
+        // the shell switches between services, so it works with the paths the device uses
+        IN_HOUSE_ARREST.get_or_init(|| false);
+        let status = shell::run(device, (!app_id.is_empty()).then_some(app_id.as_str()));
+        unsafe { idevice_free(device) };
+        std::process::exit(status.err().map_or(0, |status| status as i32));
+    }
+
     debug!("Creating lockdown client...");
     let mut client = MaybeUninit::<lockdownd_client_t>::zeroed();
     let client_ptr = client.as_mut_ptr();
@@ -161,7 +414,7 @@ fn main() {
     if res != lockdownd_error_t_LOCKDOWN_E_SUCCESS || client_ptr.is_null() {
         eprintln!("lockdown failed:{:?}", res);
         unsafe { idevice_free(device) };
-        return;
+        std::process::exit(exit_code);
     }
 
     let client = unsafe { client.assume_init() };
@@ -184,45 +437,76 @@ fn main() {
     } else {
         CString::new(AFC_SERVICE_NAME).unwrap()
     };
-    let mut descriptor = MaybeUninit::<lockdownd_service_descriptor_t>::zeroed();
-    let descriptor_ptr = descriptor.as_mut_ptr();
-    let res = unsafe { lockdownd_start_service(client, service_name.as_ptr(), descriptor_ptr) };
-    if res != lockdownd_error_t_LOCKDOWN_E_SUCCESS || descriptor_ptr.is_null() {
-        eprintln!("lockdownd_start_service failed:{:?}", res);
-        unsafe { lockdownd_client_free(client) };
-        unsafe { idevice_free(device) };
-        return;
-    }
-
-    let service_descriptor = unsafe { descriptor.assume_init() };
-
-    if let Some(afc_client) = Client::new(device, service_descriptor) {
-        if list_apps {
+    if list_apps {
+        if let Some(afc_client) = start_service(device, client, &service_name) {
             let sharing_only = args.vers.unwrap().sharing_apps.is_some();
             debug!("Start listing apps...");
             if let Some(apps) = afc_client.list_apps() {
                 print_app(sharing_only, apps);
             }
             afc_client.close();
-            unsafe { lockdownd_client_free(client) };
-            unsafe { idevice_free(device) };
-            return;
         }
+        unsafe { lockdownd_client_free(client) };
+        unsafe { idevice_free(device) };
+        return;
+    }
 
-        if use_house_arrest && afc_client.start_house_arrest(app_id) < 0 {
+    // the exit code is the AFC error of the first failed request
+    if let Some(command) = args.command {
+        let afc_client = start_service(device, client, &service_name);
+        unsafe { lockdownd_client_free(client) };
+        let status = match afc_client {
+            Some(afc_client)
+                if use_house_arrest && afc_client.start_house_arrest(app_id.clone()) < 0 =>
+            {
+                eprintln!("Cannot start_house_arrest");
+                afc_client.close();
+                Err(AfcError::ServiceNotConnected)
+            }
+            Some(afc_client) => {
+                let res = command.run(&afc_client);
+                afc_client.close();
+                res
+            }
+            None => Err(AfcError::ServiceNotConnected),
+        };
+        unsafe { idevice_free(device) };
+        std::process::exit(status.err().map_or(0, |status| status as i32));
+    }
+
+    // a lost connection is reopened on the same device, even if none was asked for
+    let service = device::udid(device).map(|udid| {
+        Arc::new(Service {
+            udid,
+            lookup,
+            app_id: use_house_arrest.then(|| app_id.clone()),
+        })
+    });
+    let window = Duration::from_secs(args.reconnect_timeout);
+
+    // every connection is a service session of its own, so file handles are per connection
+    let mut clients = Vec::new();
+    while clients.len() < args.connections.max(1) {
+        let Some(mut afc_client) = start_service(device, client, &service_name) else {
+            break;
+        };
+        if use_house_arrest && afc_client.start_house_arrest(app_id.clone()) < 0 {
             eprintln!("Cannot start_house_arrest");
-            unsafe { lockdownd_client_free(client) };
-            unsafe { idevice_free(device) };
-            return;
+            afc_client.close();
+            break;
         }
-
-        CLIENT.get_or_init(|| afc_client);
-    } else {
-        eprintln!("Cannot create AfcClient");
+        if let Some(service) = service.clone().filter(|_| !window.is_zero()) {
+            afc_client = afc_client.reconnect_with(Box::new(move || reconnect(&service)), window);
+        }
+        clients.push(afc_client);
+    }
+    if clients.is_empty() {
         unsafe { lockdownd_client_free(client) };
         unsafe { idevice_free(device) };
         return;
     }
+    debug!("{} connection(s) open", clients.len());
+    POOL.get_or_init(|| Pool::new(clients));
 
     DEVICE.get_or_init(|| device.into());
     unsafe { lockdownd_client_free(client) };
@@ -248,6 +532,97 @@ fn main() {
     };
 }
 
+/// Starts `service_name` through lockdown and connects a client to it
+fn start_service(
+    device: idevice_t,
+    client: lockdownd_client_t,
+    service_name: &CStr,
+) -> Option<Client> {
+    let mut descriptor = MaybeUninit::<lockdownd_service_descriptor_t>::zeroed();
+    let descriptor_ptr = descriptor.as_mut_ptr();
+    let res = unsafe { lockdownd_start_service(client, service_name.as_ptr(), descriptor_ptr) };
+    if res != lockdownd_error_t_LOCKDOWN_E_SUCCESS || descriptor_ptr.is_null() {
+        eprintln!("lockdownd_start_service failed:{:?}", res);
+        return None;
+    }
+
+    let service_descriptor = unsafe { descriptor.assume_init() };
+    let afc_client = unsafe { Client::new(device.cast(), service_descriptor.cast()) };
+    unsafe { lockdownd_service_descriptor_free(service_descriptor) };
+    if afc_client.is_none() {
+        eprintln!("Cannot create AfcClient");
+    }
+    afc_client
+}
+
+/// What a lost connection is opened again with
+struct Service {
+    udid: CString,
+    lookup: idevice_options,
+    app_id: Option<String>,
+}
+
+/// Looks the device up again and starts a new session of the service on it, with the
+/// app's documents vended again in house_arrest mode
+fn reconnect(service: &Service) -> Option<Box<dyn Transport>> {
+    let mut device = MaybeUninit::<idevice_t>::zeroed();
+    let res = unsafe {
+        idevice_new_with_options(device.as_mut_ptr(), service.udid.as_ptr(), service.lookup)
+    };
+    if res != idevice_error_t_IDEVICE_E_SUCCESS {
+        debug!("No device found:{:?}", res);
+        return None;
+    }
+    let device = unsafe { device.assume_init() };
+
+    match open_service(device, service.app_id.as_deref()) {
+        Some(afc_client) => {
+            // the connection may still refer to the device it was opened on
+            RECONNECTED.lock().unwrap().push(device.into());
+            Some(afc_client.into_transport())
+        }
+        None => {
+            unsafe { idevice_free(device) };
+            None
+        }
+    }
+}
+
+/// Starts a new session of the AFC service on `device`, or of house_arrest with the
+/// documents of `app_id` vended
+fn open_service(device: idevice_t, app_id: Option<&str>) -> Option<Client> {
+    let mut client = MaybeUninit::<lockdownd_client_t>::zeroed();
+    let program_name = CString::new("dokanifuse").unwrap();
+    let res = unsafe {
+        lockdownd_client_new_with_handshake(device, client.as_mut_ptr(), program_name.as_ptr())
+    };
+    if res != lockdownd_error_t_LOCKDOWN_E_SUCCESS {
+        debug!("lockdown failed:{:?}", res);
+        return None;
+    }
+    let client = unsafe { client.assume_init() };
+
+    let service_name = match app_id {
+        Some(_) => CString::new(HOUSE_ARREST_SERVICE_NAME).unwrap(),
+        None => CString::new(AFC_SERVICE_NAME).unwrap(),
+    };
+    let afc_client = start_service(device, client, &service_name);
+    unsafe { lockdownd_client_free(client) };
+    afc_client.filter(|afc_client| match app_id {
+        Some(app_id) if afc_client.start_house_arrest(app_id.to_string()) < 0 => {
+            eprintln!("Cannot start_house_arrest");
+            afc_client.close();
+            false
+        }
+        _ => true,
+    })
+}
+
+/// Connection for a request that is not tied to an open file
+fn client() -> &'static Client {
+    POOL.get().unwrap().client()
+}
+
 fn real_path(path: *const i8) -> String {
     let raw = unsafe { CStr::from_ptr(path) }
         .to_string_lossy()
@@ -268,167 +643,397 @@ fn real_path(path: *const i8) -> String {
     }
 }
 
+/// Attributes of `path`, answered from the cache while the entry is fresh
+fn file_info(path: &str) -> Result<FileInfo, AfcError> {
+    let cache = CACHE.get().unwrap();
+    if let Some(info) = cache.file_info(path) {
+        return Ok(info);
+    }
+
+    let info = client().stat(path)?;
+    cache.insert_file_info(path, &info);
+    Ok(info)
+}
+
+/// Names in the directory `path`, answered from the cache while the entry is fresh
+fn read_directory(path: &str) -> Result<Vec<String>, AfcError> {
+    let cache = CACHE.get().unwrap();
+    if let Some(names) = cache.directory(path) {
+        return Ok(names);
+    }
+
+    let names = client().list_dir(path)?;
+    cache.insert_directory(path, &names);
+    Ok(names)
+}
+
+/// Drops cached state a change to `path` made stale
+fn invalidate(path: &str) {
+    CACHE.get().unwrap().invalidate(path);
+}
+
 unsafe extern "C" fn ifuse_init(con: *mut fuse_conn_info) -> *mut c_void {
-    (*con).async_read = 0;
     std::ptr::null_mut() as _
 }
 
 unsafe extern "C" fn ifuse_cleanup(_data: *mut c_void) {
-    if CLIENT.get().unwrap().close() == 0 {
-        debug!("Connection closed...");
+    for client in POOL.get().unwrap().clients() {
+        if client.close() == 0 {
+            debug!("Connection closed...");
+        }
     }
 
     if idevice_free(DEVICE.get().unwrap().pointer()) == idevice_error_t_IDEVICE_E_SUCCESS {
         debug!("iDevice freed...")
     }
+    for device in RECONNECTED.lock().unwrap().drain(..) {
+        idevice_free(device.pointer());
+    }
 }
 
 unsafe extern "C" fn ifuse_getattr(path: *const i8, stbuf: *mut stat) -> i32 {
-    let info = CLIENT
-        .get()
-        .unwrap()
-        .get_file_info(CString::new(real_path(path)).unwrap().as_ptr());
-
     std::ptr::write_bytes(stbuf, 0, 1);
-    if let Some(list) = extract_list(info) {
-        if list.is_empty() {
-            return -1;
-        }
-        let info = to_map(list);
-
-        if let Some(st_size) = info.get("st_size") {
-            (*stbuf).st_size = st_size.parse().unwrap();
-        }
-
-        // if let Some(st_blocks) = info.get("st_blocks") {
-        //     (*stbuf).st_blocks = st_blocks.parse().unwrap();
-        // }
-
-        if let Some(st_ifmt) = info.get("st_ifmt") {
-            let mode = match st_ifmt.as_str() {
-                "S_IFREG" => S_IFREG,
-                "S_IFDIR" => S_IFDIR,
-                "S_IFLNK" => S_IFLNK,
-                "S_IFBLK" => S_IFBLK,
-                "S_IFCHR" => S_IFCHR,
-                "S_IFIFO" => S_IFIFO,
-                "S_IFSOCK" => S_IFSOCK,
-                _ => 0,
-            };
-            (*stbuf).st_mode = mode as _;
-        }
-
-        if let Some(st_nlink) = info.get("st_nlink") {
-            (*stbuf).st_nlink = st_nlink.parse().unwrap()
-        }
-
-        if let Some(st_mtim) = info.get("st_mtime") {
-            let nanos: i64 = st_mtim.parse().unwrap();
-            (*stbuf).st_mtim = timespec {
-                tv_sec: (nanos / 1_000_000_000) as _,
-                tv_nsec: (nanos % 1_000_000_000) as _,
-            }
-        }
-
-        if (*stbuf).st_mode == S_IFDIR as _ {
-            (*stbuf).st_mode |= 0o755;
-        } else if (*stbuf).st_mode == S_IFLNK as _ {
-            (*stbuf).st_mode |= 0o777;
-        } else {
-            (*stbuf).st_mode |= 0o644;
+    match file_info(&real_path(path)) {
+        Ok(info) => {
+            fill_stat(&info, &mut *stbuf);
+            0
         }
+        Err(status) => -status.to_errno(),
+    }
+}
 
-        // and set some additional info
-        (*stbuf).st_uid = 123;
-        (*stbuf).st_gid = 456;
+/// Fills `stbuf` from the attributes GET_FILE_INFO returns
+fn fill_stat(info: &FileInfo, stbuf: &mut stat) {
+    stbuf.st_size = info.size as _;
+
+    // stbuf.st_blocks = info.blocks as _;
+
+    let mode = match info.file_type {
+        FileType::File => S_IFREG,
+        FileType::Directory => S_IFDIR,
+        FileType::Symlink => S_IFLNK,
+        FileType::BlockDevice => S_IFBLK,
+        FileType::CharDevice => S_IFCHR,
+        FileType::Fifo => S_IFIFO,
+        FileType::Socket => S_IFSOCK,
+        FileType::Unknown => 0,
+    };
+    stbuf.st_mode = mode as _;
+    stbuf.st_nlink = info.nlink as _;
 
-        // (*stbuf).st_blksize = G_BLOCKSIZE as _;
+    let mtime = info.modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    stbuf.st_mtim = timespec {
+        tv_sec: mtime.as_secs() as _,
+        tv_nsec: mtime.subsec_nanos() as _,
+    };
 
-        return 0;
+    if stbuf.st_mode == S_IFDIR as _ {
+        stbuf.st_mode |= 0o755;
+    } else if stbuf.st_mode == S_IFLNK as _ {
+        stbuf.st_mode |= 0o777;
+    } else {
+        stbuf.st_mode |= 0o644;
     }
 
-    -1
+    // and set some additional info
+    stbuf.st_uid = 123;
+    stbuf.st_gid = 456;
+
+    // stbuf.st_blksize = G_BLOCKSIZE as _;
 }
 
 unsafe extern "C" fn ifuse_readdir(
     path: *const i8,
     buf: *mut c_void,
-    filter: Option<unsafe extern "C" fn(*mut c_void, *const i8, *const stat, u64) -> i32>,
+    filter: fuse_fill_dir_t,
     offset: u64,
     fi: *mut fuse_file_info,
 ) -> i32 {
-    let info = CLIENT
-        .get()
-        .unwrap()
-        .read_directory(CString::new(real_path(path)).unwrap().as_ptr());
-
-    if info.status == afc_error_t_AFC_E_SUCCESS {
-        if let Some(dirs) = extract_list(info) {
-            if let Some(filter) = filter {
-                for dir in dirs {
-                    let dir = CString::new(dir).unwrap();
-                    filter(buf, dir.as_ptr(), std::ptr::null(), 1);
-                }
+    let path = real_path(path);
+    let names = match read_directory(&path) {
+        Ok(names) => names,
+        Err(status) => return -status.to_errno(),
+    };
+
+    let stats = if *PREFETCH_ATTRS.get().unwrap() {
+        prefetch_stats(&path, &names)
+    } else {
+        vec![None; names.len()]
+    };
+
+    if let Some(filter) = filter {
+        // whether the device lists . and .. differs between services, so they are added here
+        let mut dir_stat = MaybeUninit::<stat>::zeroed().assume_init();
+        dir_stat.st_mode = (S_IFDIR | 0o755) as _;
+        for dot in [".", ".."] {
+            let dot = CString::new(dot).unwrap();
+            filter(buf, dot.as_ptr(), &dir_stat, 0);
+        }
+
+        for (name, st) in names.iter().zip(&stats) {
+            let name = CString::new(name.as_str()).unwrap();
+            let st = st.as_ref().map_or(std::ptr::null(), |st| st as *const stat);
+            if filter(buf, name.as_ptr(), st, 0) != 0 {
+                break;
             }
-            return 0;
-        } else {
-            return -1;
         }
     }
-    -1
+    0
 }
 
-unsafe extern "C" fn ifuse_statfs(path: *const i8, stats: *mut statvfs) -> i32 {
-    let info = CLIENT.get().unwrap().get_device_info();
+/// Attributes of the entries of `dir`, fetching the ones not cached in one pipelined batch
+fn prefetch_stats(dir: &str, names: &[String]) -> Vec<Option<stat>> {
+    let cache = CACHE.get().unwrap();
+    let paths: Vec<String> = names.iter().map(|name| join_path(dir, name)).collect();
+    let mut infos: Vec<Option<FileInfo>> = paths.iter().map(|p| cache.file_info(p)).collect();
+
+    let missing: Vec<usize> = (0..paths.len()).filter(|&i| infos[i].is_none()).collect();
+    if !missing.is_empty() {
+        let missing_paths: Vec<&str> = missing.iter().map(|&i| paths[i].as_str()).collect();
+        let fetched = client().stat_many(&missing_paths);
+        for (i, info) in missing.into_iter().zip(fetched) {
+            if let Ok(info) = info {
+                cache.insert_file_info(&paths[i], &info);
+                infos[i] = Some(info);
+            }
+        }
+    }
+
+    infos
+        .into_iter()
+        .map(|info| {
+            let mut st = unsafe { MaybeUninit::<stat>::zeroed().assume_init() };
+            fill_stat(&info?, &mut st);
+            Some(st)
+        })
+        .collect()
+}
 
-    if info.status != afc_error_t_AFC_E_SUCCESS {
-        return -info.status;
+fn join_path(dir: &str, name: &str) -> String {
+    if dir.ends_with('/') {
+        format!("{}{}", dir, name)
+    } else {
+        format!("{}/{}", dir, name)
     }
+}
 
-    let mut totalspace = 0u64;
-    let mut freespace = 0u64;
-    let mut blocksize = 0u64;
+unsafe extern "C" fn ifuse_statfs(path: *const i8, stats: *mut statvfs) -> i32 {
+    let info = match client().device_info() {
+        Ok(info) => info,
+        Err(status) => return -status.to_errno(),
+    };
+    let blocks = |bytes: u64| bytes.checked_div(info.block_size).unwrap_or(0);
 
-    if let Some(res) = extract_list(info) {
-        let info = to_map(res);
+    (*stats).f_bsize = info.block_size as _;
+    (*stats).f_frsize = info.block_size as _;
+    (*stats).f_blocks = blocks(info.total_bytes) as _;
+    (*stats).f_bfree = blocks(info.free_bytes) as _;
+    (*stats).f_bavail = blocks(info.free_bytes) as _;
+    (*stats).f_namemax = 255;
+    if read_only() {
+        (*stats).f_flag |= ST_RDONLY as std::os::raw::c_ulong;
+    }
+    (*stats).f_files = 1000000000;
+    (*stats).f_ffree = 1000000000;
+    0
+}
 
-        if let Some(n_str) = info.get("FSTotalBytes") {
-            totalspace = n_str.parse().unwrap();
-        }
-        if let Some(n_str) = info.get("FSFreeBytes") {
-            freespace = n_str.parse().unwrap();
-        }
-        if let Some(n_str) = info.get("FSBlockSize") {
-            blocksize = n_str.parse().unwrap();
+unsafe extern "C" fn ifuse_release(path: *const i8, fi: *mut fuse_file_info) -> i32 {
+    let (client, handle) = POOL.get().unwrap().file((*fi).fh);
+    let mut res = 0;
+    if let Some(file) = OPEN_FILES.get().unwrap().remove((*fi).fh) {
+        if let Err(status) = file.lock().unwrap().flush(client, handle) {
+            res = -status.to_errno();
         }
+        forget(path);
     }
+    // release cannot fail the close(2) that led to it
+    let _ = client.file_close(handle);
+    res
+}
 
-    (*stats).f_bsize = blocksize as _;
-    (*stats).f_frsize = blocksize as _;
-    (*stats).f_blocks = if blocksize > 0 {
-        (totalspace / blocksize) as _
+unsafe extern "C" fn ifuse_flush(path: *const i8, fi: *mut fuse_file_info) -> i32 {
+    debug!("ifuse_flush");
+    let (client, handle) = POOL.get().unwrap().file((*fi).fh);
+    let file = OPEN_FILES.get().unwrap().get((*fi).fh);
+    let res = file.lock().unwrap().flush(client, handle);
+    forget(path);
+    match res {
+        Ok(()) => 0,
+        Err(status) => -status.to_errno(),
+    }
+}
+
+unsafe extern "C" fn ifuse_ftruncate(path: *const i8, size: off_t, fi: *mut fuse_file_info) -> i32 {
+    debug!("ifuse_ftruncate");
+    if read_only() {
+        return -EROFS;
+    }
+    let (client, handle) = POOL.get().unwrap().file((*fi).fh);
+    let file = OPEN_FILES.get().unwrap().get((*fi).fh);
+    let res = file.lock().unwrap().set_size(client, handle, size as _);
+    forget(path);
+    match res {
+        Ok(()) => 0,
+        Err(status) => -status.to_errno(),
+    }
+}
+
+unsafe extern "C" fn ifuse_fgetattr(
+    path: *const i8,
+    stbuf: *mut stat,
+    fi: *mut fuse_file_info,
+) -> i32 {
+    // type and times come from the path, the size from the handle, which also
+    // knows about writes still buffered
+    let res = if path.is_null() {
+        -ENOENT
     } else {
-        0
+        ifuse_getattr(path, stbuf)
     };
-    (*stats).f_bfree = if blocksize > 0 {
-        (freespace / blocksize) as _
-    } else {
-        0
+    if res == -ENOENT {
+        // unlinked while open
+        std::ptr::write_bytes(stbuf, 0, 1);
+        (*stbuf).st_mode = (S_IFREG | 0o644) as _;
+        (*stbuf).st_nlink = 1;
+    } else if res != 0 {
+        return res;
+    }
+
+    let (client, handle) = POOL.get().unwrap().file((*fi).fh);
+    let file = OPEN_FILES.get().unwrap().get((*fi).fh);
+    let size = file.lock().unwrap().size(client, handle);
+    match size {
+        Ok(size) => {
+            (*stbuf).st_size = size as _;
+            0
+        }
+        Err(status) => -status.to_errno(),
+    }
+}
+
+/// Byte ranges are not supported by AFC, every lock covers the whole file and
+/// belongs to the open handle. FUSE's `lock_owner` is not followed: processes
+/// sharing a handle share its lock, and closing another handle of the same file
+/// does not release it
+unsafe extern "C" fn ifuse_lock(
+    path: *const i8,
+    fi: *mut fuse_file_info,
+    cmd: i32,
+    lock: *mut flock,
+) -> i32 {
+    debug!("ifuse_lock");
+    let operation = match (*lock).l_type as u32 {
+        F_RDLCK => LockOp::Shared,
+        F_WRLCK => LockOp::Exclusive,
+        F_UNLCK => LockOp::Unlock,
+        _ => return -EINVAL,
     };
-    (*stats).f_bavail = if blocksize > 0 {
-        (freespace / blocksize) as _
+    let (client, handle) = POOL.get().unwrap().file((*fi).fh);
+    let file = OPEN_FILES.get().unwrap().get((*fi).fh);
+
+    match cmd as u32 {
+        F_GETLK => {
+            let mut file = file.lock().unwrap();
+            let held = file.held_lock(client, handle);
+            // a lock of this handle only conflicts with itself when it is weaker
+            if operation == LockOp::Unlock
+                || held == Some(LockOp::Exclusive)
+                || held == Some(operation)
+            {
+                (*lock).l_type = F_UNLCK as _;
+                return 0;
+            }
+            // the device cannot be asked who holds a lock, so try to take it, then
+            // put back what the handle held since converting may have dropped it
+            let probe = file.lock(client, handle, operation);
+            let restored = file.lock(client, handle, held.unwrap_or(LockOp::Unlock));
+            if let Err(status) = restored {
+                return -status.to_errno();
+            }
+            match probe {
+                Ok(()) => {
+                    (*lock).l_type = F_UNLCK as _;
+                    0
+                }
+                Err(AfcError::OpWouldBlock) => {
+                    // next to a shared lock of this handle only shared ones can be held
+                    (*lock).l_type = if held.is_some() { F_RDLCK } else { F_WRLCK } as _;
+                    (*lock).l_whence = SEEK_SET as _;
+                    (*lock).l_start = 0;
+                    (*lock).l_len = 0;
+                    (*lock).l_pid = 0;
+                    0
+                }
+                Err(status) => -status.to_errno(),
+            }
+        }
+        F_SETLK => set_lock((*fi).fh, operation, Duration::ZERO),
+        F_SETLKW => set_lock((*fi).fh, operation, LOCK_WAIT),
+        _ => -EINVAL,
+    }
+}
+
+/// flock(2) locks, which like AFC ones cover the whole file and belong to the handle
+#[cfg(not(windows))]
+unsafe extern "C" fn ifuse_flock(path: *const i8, fi: *mut fuse_file_info, op: i32) -> i32 {
+    debug!("ifuse_flock");
+    let operation = match op as u32 & !LOCK_NB {
+        LOCK_SH => LockOp::Shared,
+        LOCK_EX => LockOp::Exclusive,
+        LOCK_UN => LockOp::Unlock,
+        _ => return -EINVAL,
+    };
+    let wait = if op as u32 & LOCK_NB == 0 {
+        LOCK_WAIT
     } else {
-        0
+        Duration::ZERO
     };
-    (*stats).f_namemax = 255;
-    (*stats).f_files = 1000000000;
-    (*stats).f_ffree = 1000000000;
-    0
+    set_lock((*fi).fh, operation, wait)
 }
 
-unsafe extern "C" fn ifuse_release(path: *const i8, fi: *mut fuse_file_info) -> i32 {
-    CLIENT.get().unwrap().file_close((*fi).fh);
-    0
+/// How long a blocking lock request waits for the holder before failing with EAGAIN
+const LOCK_WAIT: Duration = Duration::from_secs(60);
+
+/// Takes, converts or releases the lock of the open file `fh`. The device never
+/// waits, so the lock is polled for up to `wait` until the holder lets go or the
+/// request is interrupted
+fn set_lock(fh: u64, operation: LockOp, wait: Duration) -> i32 {
+    let (client, handle) = POOL.get().unwrap().file(fh);
+    let file = OPEN_FILES.get().unwrap().get(fh);
+    let deadline = Instant::now() + wait;
+    loop {
+        let res = file.lock().unwrap().lock(client, handle, operation);
+        match res {
+            Ok(()) => return 0,
+            Err(AfcError::OpWouldBlock) if Instant::now() < deadline => {
+                if interrupted() {
+                    return -EINTR;
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err(status) => return -status.to_errno(),
+        }
+    }
+}
+
+/// Whether the kernel gave up on the request being served, as when the waiting
+/// process got a signal; never outside of one
+#[cfg(not(windows))]
+fn interrupted() -> bool {
+    unsafe { !fuse_get_context().is_null() && fuse_interrupted() != 0 }
+}
+
+#[cfg(windows)]
+fn interrupted() -> bool {
+    false
+}
+
+/// Drops the cached attributes of `path` once buffered writes reached the device
+unsafe fn forget(path: *const i8) {
+    if !path.is_null() {
+        CACHE.get().unwrap().forget(&real_path(path));
+    }
 }
 
 unsafe extern "C" fn ifuse_opendir(path: *const i8, fi: *mut fuse_file_info) -> i32 {
@@ -440,54 +1045,61 @@ unsafe extern "C" fn ifuse_releasedir(path: *const i8, fi: *mut fuse_file_info)
 }
 
 unsafe extern "C" fn ifuse_open(path: *const i8, fi: *mut fuse_file_info) -> i32 {
-    let mode = get_afc_file_mode((*fi).flags as _);
+    let Some(mode) = get_afc_file_mode((*fi).flags as _) else {
+        return if read_only() { -EROFS } else { -EINVAL };
+    };
 
-    if mode == 0 {
-        return -EPERM;
+    let path = real_path(path);
+    let (index, client) = POOL.get().unwrap().pick();
+    let res = client.open_file(&path, mode);
+    // any mode but read-only may create or truncate the file
+    if mode != OpenMode::ReadOnly {
+        invalidate(&path);
     }
 
-    let info = CLIENT
-        .get()
-        .unwrap()
-        .file_open(CString::new(real_path(path)).unwrap().as_ptr(), mode);
-
-    if let Some(res) = extract_num(info) {
-        (*fi).fh = res;
-        return 0;
+    match res {
+        Ok(handle) => {
+            (*fi).fh = Pool::bind(index, handle);
+            let append = matches!(mode, OpenMode::Append | OpenMode::ReadAppend);
+            OPEN_FILES.get().unwrap().insert((*fi).fh, append);
+            0
+        }
+        Err(status) => -status.to_errno(),
     }
-
-    -EPERM
 }
 
-fn get_afc_file_mode(flags: u32) -> afc_file_mode_t {
+fn get_afc_file_mode(flags: u32) -> Option<OpenMode> {
+    if read_only() && flags & O_ACCMODE != O_RDONLY {
+        return None;
+    }
     match flags & O_ACCMODE {
-        O_RDONLY => afc_file_mode_t_AFC_FOPEN_RDONLY,
+        O_RDONLY => Some(OpenMode::ReadOnly),
         O_WRONLY => {
             if (flags & O_TRUNC) == O_TRUNC {
-                afc_file_mode_t_AFC_FOPEN_WRONLY
+                Some(OpenMode::Truncate)
             } else if (flags & O_APPEND) == O_APPEND {
-                afc_file_mode_t_AFC_FOPEN_APPEND
+                Some(OpenMode::Append)
             } else {
-                afc_file_mode_t_AFC_FOPEN_RW
+                Some(OpenMode::ReadWrite)
             }
         }
         O_RDWR => {
             if (flags & O_TRUNC) == O_TRUNC {
-                afc_file_mode_t_AFC_FOPEN_WR
+                Some(OpenMode::ReadTruncate)
             } else if (flags & O_APPEND) == O_APPEND {
-                afc_file_mode_t_AFC_FOPEN_RDAPPEND
+                Some(OpenMode::ReadAppend)
             } else {
-                afc_file_mode_t_AFC_FOPEN_RW
+                Some(OpenMode::ReadWrite)
             }
         }
-        _ => 0,
+        _ => None,
     }
 }
 
 unsafe extern "C" fn ifuse_read(
     path: *const i8,
     buf: *mut i8,
-    size: u32,
+    size: size_t,
     offset: u64,
     fi: *mut fuse_file_info,
 ) -> i32 {
@@ -495,21 +1107,12 @@ unsafe extern "C" fn ifuse_read(
         return 0;
     }
 
-    let info = CLIENT
-        .get()
-        .unwrap()
-        .file_seek((*fi).fh, offset as i64, SEEK_SET as _);
-
-    if info.status != afc_error_t_AFC_E_SUCCESS {
-        return -info.status;
-    }
-
-    let info = CLIENT.get().unwrap().file_read((*fi).fh, size as _);
-
-    let mut bytes = Vec::new();
-    if let Some(byte) = extract_byte(info) {
-        bytes.extend(byte);
-    }
+    let (client, handle) = POOL.get().unwrap().file((*fi).fh);
+    let file = OPEN_FILES.get().unwrap().get((*fi).fh);
+    let bytes = match file.lock().unwrap().read(client, handle, offset, size as _) {
+        Ok(bytes) => bytes,
+        Err(status) => return -status.to_errno(),
+    };
 
     if !bytes.is_empty() {
         let len = bytes.len().min(size as _);
@@ -520,113 +1123,247 @@ unsafe extern "C" fn ifuse_read(
     }
 }
 
-unsafe extern "C" fn ifuse_create(path: *const i8, mode: u64, fi: *mut fuse_file_info) -> i32 {
+unsafe extern "C" fn ifuse_create(path: *const i8, mode: mode_t, fi: *mut fuse_file_info) -> i32 {
     debug!("ifuse_create");
+    if read_only() {
+        return -EROFS;
+    }
     ifuse_open(path, fi)
 }
 
 unsafe extern "C" fn ifuse_write(
     path: *const i8,
     buf: *const i8,
-    size: u32,
+    size: size_t,
     offset: u64,
     fi: *mut fuse_file_info,
 ) -> i32 {
     debug!("ifuse_write");
+    if read_only() {
+        return -EROFS;
+    }
     if size == 0 {
         return 0;
     }
 
-    let info = CLIENT
-        .get()
-        .unwrap()
-        .file_seek((*fi).fh, offset as _, SEEK_SET as _);
-    if info.status != afc_error_t_AFC_E_SUCCESS {
-        return -info.status;
-    }
-
-    let info = CLIENT.get().unwrap().file_write((*fi).fh, buf, size);
-    if info.status != afc_error_t_AFC_E_SUCCESS {
-        return -info.status;
+    let (client, handle) = POOL.get().unwrap().file((*fi).fh);
+    let file = OPEN_FILES.get().unwrap().get((*fi).fh);
+    let data = std::slice::from_raw_parts(buf as *const u8, size as _);
+    let res = file.lock().unwrap().write(client, handle, offset, data);
+    forget(path);
+    if let Err(status) = res {
+        return -status.to_errno();
     }
     size as _
 }
 
 unsafe extern "C" fn ifuse_truncate(path: *const i8, size: u64) -> i32 {
     debug!("ifuse_truncate");
-    let info = CLIENT
-        .get()
-        .unwrap()
-        .truncate(CString::new(real_path(path)).unwrap().as_ptr(), size);
-    if info.status != afc_error_t_AFC_E_SUCCESS {
-        return -info.status;
+    if read_only() {
+        return -EROFS;
     }
-    0
+    let path = real_path(path);
+    let res = client().set_len(&path, size);
+    invalidate(&path);
+    errno(res)
 }
 
 unsafe extern "C" fn ifuse_unlink(path: *const i8) -> i32 {
     debug!("ifuse_unlink");
-    let info = CLIENT
-        .get()
-        .unwrap()
-        .remove_path(CString::new(real_path(path)).unwrap().as_ptr());
-    if info.status != afc_error_t_AFC_E_SUCCESS {
-        return -info.status;
+    if read_only() {
+        return -EROFS;
     }
-    0
+    let path = real_path(path);
+    let res = client().remove(&path);
+    invalidate(&path);
+    errno(res)
 }
 
-unsafe extern "C" fn ifuse_mkdir(path: *const i8, ignored: u64) -> i32 {
-    debug!("ifuse_mkdir");
+unsafe extern "C" fn ifuse_rmdir(path: *const i8) -> i32 {
+    debug!("ifuse_rmdir");
+    if read_only() {
+        return -EROFS;
+    }
+    let path = real_path(path);
+    let res = if *RECURSIVE_DELETE.get().unwrap() {
+        client().remove_all(&path)
+    } else {
+        client().remove(&path)
+    };
+    invalidate(&path);
+    errno(res)
+}
 
-    let info = CLIENT
-        .get()
-        .unwrap()
-        .make_directory(CString::new(real_path(path)).unwrap().as_ptr());
-    if info.status != afc_error_t_AFC_E_SUCCESS {
-        return -info.status;
+unsafe extern "C" fn ifuse_mkdir(path: *const i8, ignored: mode_t) -> i32 {
+    debug!("ifuse_mkdir");
+    if read_only() {
+        return -EROFS;
     }
-    0
+
+    let path = real_path(path);
+    let res = client().create_dir(&path);
+    invalidate(&path);
+    errno(res)
 }
 
 unsafe extern "C" fn ifuse_fsync(path: *const i8, datasync: i32, fi: *mut fuse_file_info) -> i32 {
     debug!("ifuse_fsync");
-    0
+    ifuse_flush(path, fi)
 }
 
-unsafe extern "C" fn ifuse_chmod(path: *const i8, mode: u64) -> i32 {
-    debug!("ifuse_fsync");
+unsafe extern "C" fn ifuse_chmod(path: *const i8, mode: mode_t) -> i32 {
+    debug!("ifuse_chmod");
+    if read_only() {
+        return -EROFS;
+    }
     0
 }
 
 unsafe extern "C" fn ifuse_chown(file: *const i8, user: u32, group: u32) -> i32 {
-    debug!("ifuse_fsync");
+    debug!("ifuse_chown");
+    if read_only() {
+        return -EROFS;
+    }
     0
 }
 
-unsafe extern "C" fn ifuse_readlink(a: *const i8, b: *mut i8, c: u32) -> i32 {
+unsafe extern "C" fn ifuse_readlink(path: *const i8, buf: *mut i8, size: size_t) -> i32 {
     debug!("ifuse_readlink");
+    if size == 0 {
+        return -EINVAL;
+    }
+
+    let target = match file_info(&real_path(path)).map(|info| info.link_target) {
+        Ok(Some(target)) => target,
+        Ok(None) => return -EINVAL,
+        Err(status) => return -status.to_errno(),
+    };
+
+    // the target is truncated to fit and always null-terminated
+    let len = target.len().min(size as usize - 1);
+    std::ptr::copy_nonoverlapping(target.as_ptr(), buf as *mut u8, len);
+    *buf.add(len) = 0;
     0
 }
 
-unsafe extern "C" fn ifuse_symlink(a: *const i8, b: *const i8) -> i32 {
+unsafe extern "C" fn ifuse_symlink(target: *const i8, link_name: *const i8) -> i32 {
     debug!("ifuse_symlink");
-    0
+    if read_only() {
+        return -EROFS;
+    }
+    // the target is stored as given, only the link itself lives under Documents
+    let link_name = real_path(link_name);
+    let res = client().symlink(&CStr::from_ptr(target).to_string_lossy(), &link_name);
+    invalidate(&link_name);
+    errno(res)
 }
 
-unsafe extern "C" fn ifuse_link(a: *const i8, b: *const i8) -> i32 {
+unsafe extern "C" fn ifuse_link(target: *const i8, link_name: *const i8) -> i32 {
     debug!("ifuse_link");
-    0
+    if read_only() {
+        return -EROFS;
+    }
+    let target = real_path(target);
+    let link_name = real_path(link_name);
+    let res = client().hard_link(&target, &link_name);
+    // the target gains a link
+    invalidate(&target);
+    invalidate(&link_name);
+    errno(res)
 }
 
-unsafe extern "C" fn ifuse_rename(a: *const i8, b: *const i8) -> i32 {
+unsafe extern "C" fn ifuse_rename(from: *const i8, to: *const i8) -> i32 {
     debug!("ifuse_rename");
-    0
+    if read_only() {
+        return -EROFS;
+    }
+    let from = real_path(from);
+    let to = real_path(to);
+    let res = rename(client(), &from, &to);
+    invalidate(&from);
+    invalidate(&to);
+    errno(res)
+}
+
+/// Renames like rename(2), replacing an existing file `to`. The device refuses to,
+/// so `to` is moved aside first and only removed once `from` took its place.
+fn rename(client: &Client, from: &str, to: &str) -> Result<(), AfcError> {
+    match client.rename(from, to) {
+        Err(AfcError::ObjectExists) if is_file(client, to) => {}
+        res => return res,
+    }
+    let aside = aside_path(to);
+    client.rename(to, &aside)?;
+    if let Err(status) = client.rename(from, to) {
+        // whatever went wrong, the original must not be lost
+        if let Err(restore) = client.rename(&aside, to) {
+            eprintln!("Cannot move {} back to {}: {}", aside, to, restore);
+        }
+        return Err(status);
+    }
+    if let Err(status) = client.remove(&aside) {
+        debug!("Cannot remove {}: {}", aside, status);
+    }
+    Ok(())
+}
+
+/// Hidden name next to `path` to keep a replaced file under until the rename is done
+fn aside_path(path: &str) -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+    format!(
+        "{}/.{}.ifuse-{}-{}",
+        dir,
+        name,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::SeqCst)
+    )
+}
+
+fn is_file(client: &Client, path: &str) -> bool {
+    client.stat(path).is_ok_and(|info| info.is_file())
 }
 
-unsafe extern "C" fn ifuse_utimens(a: *const i8, b: *const timespec) -> i32 {
+unsafe extern "C" fn ifuse_utimens(path: *const i8, tv: *const timespec) -> i32 {
     debug!("ifuse_utimens");
-    0
+    if read_only() {
+        return -EROFS;
+    }
+    // tv[0] is atime, which AFC does not store
+    let mtime = if tv.is_null() {
+        UTIME_NOW as _
+    } else {
+        (*tv.add(1)).tv_nsec
+    };
+
+    if mtime == UTIME_OMIT as _ {
+        return 0;
+    }
+
+    let time = if mtime == UTIME_NOW as _ {
+        SystemTime::now()
+    } else {
+        let tv = *tv.add(1);
+        UNIX_EPOCH + Duration::new(tv.tv_sec as _, tv.tv_nsec as _)
+    };
+
+    let path = real_path(path);
+    let res = client().set_modified(&path, time);
+    CACHE.get().unwrap().forget(&path);
+
+    // older devices do not know SET_FILE_TIME, tell the caller the time was not kept
+    if res == Err(AfcError::UnknownPacketType) {
+        return -ENOSYS;
+    }
+    errno(res)
+}
+
+/// What a callback returns for the result of a request
+fn errno(res: Result<(), AfcError>) -> i32 {
+    match res {
+        Ok(()) => 0,
+        Err(status) => -status.to_errno(),
+    }
 }
 
 pub fn get_fuse_operations() -> fuse_operations {
@@ -635,7 +1372,7 @@ pub fn get_fuse_operations() -> fuse_operations {
         statfs: Some(ifuse_statfs),
         readdir: Some(ifuse_readdir),
         mkdir: Some(ifuse_mkdir),
-        rmdir: Some(ifuse_unlink),
+        rmdir: Some(ifuse_rmdir),
         create: Some(ifuse_create),
         open: Some(ifuse_open),
         read: Some(ifuse_read),
@@ -657,7 +1394,7 @@ pub fn get_fuse_operations() -> fuse_operations {
         getdir: None,
         mknod: None,
         utime: None,
-        flush: None,
+        flush: Some(ifuse_flush),
         setxattr: None,
         getxattr: None,
         listxattr: None,
@@ -666,9 +1403,607 @@ pub fn get_fuse_operations() -> fuse_operations {
         releasedir: Some(ifuse_releasedir),
         fsyncdir: None,
         access: None,
-        ftruncate: None,
-        fgetattr: None,
-        lock: None,
+        ftruncate: Some(ifuse_ftruncate),
+        fgetattr: Some(ifuse_fgetattr),
+        lock: if *LOCKING.get().unwrap() {
+            Some(ifuse_lock)
+        } else {
+            None
+        },
         bmap: None,
+        #[cfg(not(windows))]
+        flags: 0,
+        #[cfg(not(windows))]
+        ioctl: None,
+        #[cfg(not(windows))]
+        poll: None,
+        #[cfg(not(windows))]
+        write_buf: None,
+        #[cfg(not(windows))]
+        read_buf: None,
+        #[cfg(not(windows))]
+        flock: if *LOCKING.get().unwrap() {
+            Some(ifuse_flock)
+        } else {
+            None
+        },
+        #[cfg(not(windows))]
+        fallocate: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+    use std::{
+        ops::Deref,
+        sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    };
+
+    /// Held shared by the tests using the mount, and alone by one making it read-only
+    static MOUNTED: RwLock<()> = RwLock::new(());
+
+    /// The mount every test goes through, writable while this is held
+    struct Mount {
+        server: &'static MockServer,
+        _shared: RwLockReadGuard<'static, ()>,
+    }
+
+    impl Deref for Mount {
+        type Target = MockServer;
+
+        fn deref(&self) -> &MockServer {
+            self.server
+        }
+    }
+
+    /// The mount made read-only until this is dropped
+    struct ReadOnlyMount {
+        server: &'static MockServer,
+        _alone: RwLockWriteGuard<'static, ()>,
+    }
+
+    impl Drop for ReadOnlyMount {
+        fn drop(&mut self) {
+            READ_ONLY.store(false, Ordering::Relaxed);
+        }
+    }
+
+    fn mount() -> Mount {
+        let shared = MOUNTED.read().unwrap_or_else(PoisonError::into_inner);
+        Mount {
+            server: server(),
+            _shared: shared,
+        }
+    }
+
+    fn read_only_mount() -> ReadOnlyMount {
+        let alone = MOUNTED.write().unwrap_or_else(PoisonError::into_inner);
+        READ_ONLY.store(true, Ordering::Relaxed);
+        ReadOnlyMount {
+            server: server(),
+            _alone: alone,
+        }
+    }
+
+    fn server() -> &'static MockServer {
+        static SERVER: OnceLock<MockServer> = OnceLock::new();
+        SERVER.get_or_init(|| {
+            VERBOSE.get_or_init(|| false);
+            IN_HOUSE_ARREST.get_or_init(|| false);
+            RECURSIVE_DELETE.get_or_init(|| false);
+            CACHE.get_or_init(|| Cache::new(Duration::from_secs(60), 100));
+            PREFETCH_ATTRS.get_or_init(|| true);
+            LOCKING.get_or_init(|| true);
+            OPEN_FILES.get_or_init(|| OpenFiles::new(64 << 10, 64 << 10));
+            let (server, client) = MockServer::start();
+            POOL.get_or_init(|| Pool::new(vec![client, server.connect()]));
+            server
+        })
+    }
+
+    fn c(path: &str) -> CString {
+        CString::new(path).unwrap()
+    }
+
+    fn getattr(path: &str) -> (i32, stat) {
+        let mut st = MaybeUninit::<stat>::zeroed();
+        let res = unsafe { ifuse_getattr(c(path).as_ptr(), st.as_mut_ptr()) };
+        (res, unsafe { st.assume_init() })
+    }
+
+    #[test]
+    fn getattr_missing_is_enoent() {
+        let _mount = mount();
+        assert_eq!(getattr("/fuse_missing").0, -ENOENT);
+    }
+
+    #[test]
+    fn mkdir_and_rmdir() {
+        let server = mount();
+        assert_eq!(unsafe { ifuse_mkdir(c("/fuse_dir").as_ptr(), 0) }, 0);
+        let (res, st) = getattr("/fuse_dir");
+        assert_eq!(res, 0);
+        assert_eq!(st.st_mode & S_IFMT as mode_t, S_IFDIR as mode_t);
+
+        std::fs::write(server.root().join("fuse_dir/file"), b"x").unwrap();
+        assert_eq!(unsafe { ifuse_rmdir(c("/fuse_dir").as_ptr()) }, -ENOTEMPTY);
+    }
+
+    #[test]
+    fn open_write_read() {
+        let _mount = mount();
+        let mut fi = unsafe { MaybeUninit::<fuse_file_info>::zeroed().assume_init() };
+        fi.flags = (O_RDWR | O_CREAT) as _;
+        assert_eq!(unsafe { ifuse_open(c("/fuse_rw").as_ptr(), &mut fi) }, 0);
+
+        let data = b"0123456789";
+        let written =
+            unsafe { ifuse_write(c("/fuse_rw").as_ptr(), data.as_ptr() as _, 10, 0, &mut fi) };
+        assert_eq!(written, 10);
+
+        let mut buf = [0u8; 4];
+        let read =
+            unsafe { ifuse_read(c("/fuse_rw").as_ptr(), buf.as_mut_ptr() as _, 4, 3, &mut fi) };
+        assert_eq!(read, 4);
+        assert_eq!(&buf, b"3456");
+        assert_eq!(unsafe { ifuse_release(c("/fuse_rw").as_ptr(), &mut fi) }, 0);
+    }
+
+    #[test]
+    fn cached_attributes_follow_writes_through_the_mount() {
+        let server = mount();
+        std::fs::write(server.root().join("fuse_cached"), b"abc").unwrap();
+        assert_eq!(getattr("/fuse_cached").1.st_size, 3);
+
+        // changes made behind the mount's back stay hidden until the entry expires
+        std::fs::write(server.root().join("fuse_cached"), b"abcdef").unwrap();
+        assert_eq!(getattr("/fuse_cached").1.st_size, 3);
+
+        let mut fi = unsafe { MaybeUninit::<fuse_file_info>::zeroed().assume_init() };
+        fi.flags = O_WRONLY as _;
+        assert_eq!(
+            unsafe { ifuse_open(c("/fuse_cached").as_ptr(), &mut fi) },
+            0
+        );
+        let written = unsafe {
+            ifuse_write(
+                c("/fuse_cached").as_ptr(),
+                b"x".as_ptr() as _,
+                1,
+                9,
+                &mut fi,
+            )
+        };
+        assert_eq!(written, 1);
+        assert_eq!(
+            unsafe { ifuse_release(c("/fuse_cached").as_ptr(), &mut fi) },
+            0
+        );
+        assert_eq!(getattr("/fuse_cached").1.st_size, 10);
+
+        assert_eq!(unsafe { ifuse_unlink(c("/fuse_cached").as_ptr()) }, 0);
+        assert_eq!(getattr("/fuse_cached").0, -ENOENT);
+    }
+
+    unsafe extern "C" fn collect(
+        buf: *mut c_void,
+        name: *const i8,
+        st: *const stat,
+        _offset: u64,
+    ) -> i32 {
+        let entries = &mut *(buf as *mut Vec<(String, Option<stat>)>);
+        let name = CStr::from_ptr(name).to_string_lossy().to_string();
+        entries.push((name, st.as_ref().copied()));
+        0
+    }
+
+    #[test]
+    fn readdir_fills_stat_and_dot_entries_once() {
+        let server = mount();
+        std::fs::create_dir_all(server.root().join("fuse_list/sub")).unwrap();
+        std::fs::write(server.root().join("fuse_list/file"), b"12345").unwrap();
+
+        let mut entries: Vec<(String, Option<stat>)> = Vec::new();
+        let res = unsafe {
+            ifuse_readdir(
+                c("/fuse_list").as_ptr(),
+                &mut entries as *mut _ as *mut c_void,
+                Some(collect),
+                0,
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(res, 0);
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec![".", "..", "file", "sub"]);
+        let file = entries[2].1.unwrap();
+        assert_eq!(file.st_size, 5);
+        assert_eq!(file.st_mode & S_IFMT as mode_t, S_IFREG as mode_t);
+        let sub = entries[3].1.unwrap();
+        assert_eq!(sub.st_mode & S_IFMT as mode_t, S_IFDIR as mode_t);
+    }
+
+    fn open(path: &str, flags: u32) -> fuse_file_info {
+        let mut fi = unsafe { MaybeUninit::<fuse_file_info>::zeroed().assume_init() };
+        fi.flags = flags as _;
+        assert_eq!(unsafe { ifuse_open(c(path).as_ptr(), &mut fi) }, 0);
+        fi
+    }
+
+    fn read(path: &str, fi: &mut fuse_file_info, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        let read = unsafe {
+            ifuse_read(
+                c(path).as_ptr(),
+                buf.as_mut_ptr() as _,
+                len as _,
+                offset,
+                fi,
+            )
+        };
+        assert!(read >= 0, "read failed: {}", read);
+        buf.truncate(read as usize);
+        buf
+    }
+
+    #[test]
+    fn handles_stay_on_the_connection_that_opened_them() {
+        let server = mount();
+        std::fs::write(server.root().join("fuse_pool_a"), b"aaaa").unwrap();
+        std::fs::write(server.root().join("fuse_pool_b"), b"bbbb").unwrap();
+
+        let mut a = open("/fuse_pool_a", O_RDONLY);
+        let mut b = open("/fuse_pool_b", O_RDONLY);
+        assert_ne!(a.fh, b.fh);
+        assert_eq!(read("/fuse_pool_a", &mut a, 1, 8), b"aaa");
+        assert_eq!(read("/fuse_pool_b", &mut b, 0, 8), b"bbbb");
+        assert_eq!(
+            unsafe { ifuse_release(c("/fuse_pool_a").as_ptr(), &mut a) },
+            0
+        );
+        assert_eq!(
+            unsafe { ifuse_release(c("/fuse_pool_b").as_ptr(), &mut b) },
+            0
+        );
+    }
+
+    #[test]
+    fn concurrent_writes_to_one_handle_land_at_their_offsets() {
+        let _mount = mount();
+        let fi = open("/fuse_parallel", O_RDWR | O_TRUNC);
+        let fh = fi.fh;
+        let threads: Vec<_> = (0..8u8)
+            .map(|i| {
+                std::thread::spawn(move || {
+                    let mut fi = unsafe { MaybeUninit::<fuse_file_info>::zeroed().assume_init() };
+                    fi.fh = fh;
+                    let data = [b'a' + i; 16];
+                    for _ in 0..20 {
+                        let written = unsafe {
+                            ifuse_write(
+                                c("/fuse_parallel").as_ptr(),
+                                data.as_ptr() as _,
+                                16,
+                                i as u64 * 16,
+                                &mut fi,
+                            )
+                        };
+                        assert_eq!(written, 16);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut fi = fi;
+        let data = read("/fuse_parallel", &mut fi, 0, 256);
+        for (i, chunk) in data.chunks(16).enumerate() {
+            assert_eq!(chunk, [b'a' + i as u8; 16]);
+        }
+        assert_eq!(
+            unsafe { ifuse_release(c("/fuse_parallel").as_ptr(), &mut fi) },
+            0
+        );
+    }
+
+    #[test]
+    fn read_only_mount_refuses_changes() {
+        let mount = read_only_mount();
+        let root = mount.server.root();
+        std::fs::write(root.join("fuse_ro"), b"data").unwrap();
+        std::fs::create_dir_all(root.join("fuse_ro_dir")).unwrap();
+        let path = c("/fuse_ro");
+
+        let mut fi = unsafe { MaybeUninit::<fuse_file_info>::zeroed().assume_init() };
+        fi.flags = O_RDWR as _;
+        assert_eq!(unsafe { ifuse_open(path.as_ptr(), &mut fi) }, -EROFS);
+        let res = unsafe { ifuse_create(c("/fuse_ro_new").as_ptr(), 0o644, &mut fi) };
+        assert_eq!(res, -EROFS);
+
+        let mut fi = open("/fuse_ro", O_RDONLY);
+        let res = unsafe { ifuse_write(path.as_ptr(), b"x".as_ptr() as _, 1, 0, &mut fi) };
+        assert_eq!(res, -EROFS);
+        let res = unsafe { ifuse_ftruncate(path.as_ptr(), 0, &mut fi) };
+        assert_eq!(res, -EROFS);
+        assert_eq!(unsafe { ifuse_release(path.as_ptr(), &mut fi) }, 0);
+
+        assert_eq!(unsafe { ifuse_truncate(path.as_ptr(), 0) }, -EROFS);
+        assert_eq!(unsafe { ifuse_chmod(path.as_ptr(), 0o600) }, -EROFS);
+        assert_eq!(unsafe { ifuse_chown(path.as_ptr(), 0, 0) }, -EROFS);
+        let res = unsafe { ifuse_mkdir(c("/fuse_ro_new").as_ptr(), 0o755) };
+        assert_eq!(res, -EROFS);
+        assert_eq!(unsafe { ifuse_rmdir(c("/fuse_ro_dir").as_ptr()) }, -EROFS);
+        let res = unsafe { ifuse_symlink(path.as_ptr(), c("/fuse_ro_new").as_ptr()) };
+        assert_eq!(res, -EROFS);
+        let res = unsafe { ifuse_link(path.as_ptr(), c("/fuse_ro_new").as_ptr()) };
+        assert_eq!(res, -EROFS);
+        assert_eq!(unsafe { ifuse_unlink(path.as_ptr()) }, -EROFS);
+        let res = unsafe { ifuse_rename(path.as_ptr(), c("/fuse_ro_moved").as_ptr()) };
+        assert_eq!(res, -EROFS);
+        let res = unsafe { ifuse_utimens(path.as_ptr(), std::ptr::null()) };
+        assert_eq!(res, -EROFS);
+
+        let mut stats = MaybeUninit::<statvfs>::zeroed();
+        assert_eq!(
+            unsafe { ifuse_statfs(path.as_ptr(), stats.as_mut_ptr()) },
+            0
+        );
+        let stats = unsafe { stats.assume_init() };
+        assert_ne!(stats.f_flag & ST_RDONLY as std::os::raw::c_ulong, 0);
+
+        assert_eq!(std::fs::read(root.join("fuse_ro")).unwrap(), b"data");
+        assert!(root.join("fuse_ro_dir").is_dir());
+        assert!(!root.join("fuse_ro_new").exists());
+        assert!(!root.join("fuse_ro_moved").exists());
+    }
+
+    #[test]
+    fn ftruncate_and_fgetattr_use_the_open_handle() {
+        let server = mount();
+        std::fs::write(server.root().join("fuse_ftrunc"), b"0123456789").unwrap();
+        let mut fi = open("/fuse_ftrunc", O_RDWR);
+
+        let data = b"abcdefghijklmnopqrstuvwxyz";
+        let written = unsafe {
+            ifuse_write(
+                c("/fuse_ftrunc").as_ptr(),
+                data.as_ptr() as _,
+                26,
+                10,
+                &mut fi,
+            )
+        };
+        assert_eq!(written, 26);
+
+        let mut st = MaybeUninit::<stat>::zeroed();
+        let res = unsafe { ifuse_fgetattr(c("/fuse_ftrunc").as_ptr(), st.as_mut_ptr(), &mut fi) };
+        assert_eq!(res, 0);
+        let st = unsafe { st.assume_init() };
+        assert_eq!(st.st_size, 36);
+        assert_eq!(st.st_mode & S_IFMT as mode_t, S_IFREG as mode_t);
+
+        assert_eq!(
+            unsafe { ifuse_ftruncate(c("/fuse_ftrunc").as_ptr(), 4, &mut fi) },
+            0
+        );
+        assert_eq!(
+            std::fs::read(server.root().join("fuse_ftrunc")).unwrap(),
+            b"0123"
+        );
+        assert_eq!(getattr("/fuse_ftrunc").1.st_size, 4);
+        assert_eq!(
+            unsafe { ifuse_flush(c("/fuse_ftrunc").as_ptr(), &mut fi) },
+            0
+        );
+        assert_eq!(
+            unsafe { ifuse_release(c("/fuse_ftrunc").as_ptr(), &mut fi) },
+            0
+        );
+    }
+
+    fn lock(path: &str, fi: &mut fuse_file_info, cmd: u32, l_type: u32) -> (i32, u32) {
+        let mut lock = unsafe { MaybeUninit::<flock>::zeroed().assume_init() };
+        lock.l_type = l_type as _;
+        let res = unsafe { ifuse_lock(c(path).as_ptr(), fi, cmd as _, &mut lock) };
+        (res, lock.l_type as u32)
+    }
+
+    #[test]
+    fn locks_conflict_between_handles() {
+        let server = mount();
+        std::fs::write(server.root().join("fuse_lock"), b"x").unwrap();
+        let mut first = open("/fuse_lock", O_RDWR);
+        let mut second = open("/fuse_lock", O_RDONLY);
+
+        assert_eq!(lock("/fuse_lock", &mut first, F_SETLK, F_WRLCK).0, 0);
+        assert_eq!(lock("/fuse_lock", &mut second, F_SETLK, F_RDLCK).0, -EAGAIN);
+        assert_eq!(
+            lock("/fuse_lock", &mut second, F_GETLK, F_RDLCK),
+            (0, F_WRLCK)
+        );
+
+        assert_eq!(lock("/fuse_lock", &mut first, F_SETLK, F_UNLCK).0, 0);
+        assert_eq!(
+            lock("/fuse_lock", &mut second, F_GETLK, F_RDLCK),
+            (0, F_UNLCK)
+        );
+        assert_eq!(lock("/fuse_lock", &mut second, F_SETLK, F_RDLCK).0, 0);
+        // shared locks do not exclude each other
+        assert_eq!(lock("/fuse_lock", &mut first, F_SETLK, F_RDLCK).0, 0);
+
+        // a blocking request gives up once the wait runs out
+        let started = Instant::now();
+        let res = set_lock(second.fh, LockOp::Exclusive, Duration::from_millis(300));
+        assert_eq!(res, -EAGAIN);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        // closing a handle releases its lock
+        assert_eq!(
+            unsafe { ifuse_release(c("/fuse_lock").as_ptr(), &mut second) },
+            0
+        );
+        assert_eq!(lock("/fuse_lock", &mut first, F_SETLKW, F_WRLCK).0, 0);
+        assert_eq!(
+            unsafe { ifuse_release(c("/fuse_lock").as_ptr(), &mut first) },
+            0
+        );
+    }
+
+    #[test]
+    fn getlk_sees_other_shared_holders() {
+        let server = mount();
+        std::fs::write(server.root().join("fuse_getlk"), b"x").unwrap();
+        let mut first = open("/fuse_getlk", O_RDWR);
+        let mut second = open("/fuse_getlk", O_RDONLY);
+
+        assert_eq!(lock("/fuse_getlk", &mut first, F_SETLK, F_RDLCK).0, 0);
+        assert_eq!(
+            lock("/fuse_getlk", &mut first, F_GETLK, F_RDLCK),
+            (0, F_UNLCK)
+        );
+        assert_eq!(lock("/fuse_getlk", &mut second, F_SETLK, F_RDLCK).0, 0);
+        assert_eq!(
+            lock("/fuse_getlk", &mut first, F_GETLK, F_WRLCK),
+            (0, F_RDLCK)
+        );
+        // asking left the shared lock in place
+        assert_eq!(
+            lock("/fuse_getlk", &mut second, F_SETLK, F_WRLCK).0,
+            -EAGAIN
+        );
+
+        assert_eq!(lock("/fuse_getlk", &mut second, F_SETLK, F_UNLCK).0, 0);
+        assert_eq!(
+            lock("/fuse_getlk", &mut first, F_GETLK, F_WRLCK),
+            (0, F_UNLCK)
+        );
+        assert_eq!(
+            lock("/fuse_getlk", &mut second, F_SETLK, F_WRLCK).0,
+            -EAGAIN
+        );
+
+        for fi in [&mut first, &mut second] {
+            assert_eq!(unsafe { ifuse_release(c("/fuse_getlk").as_ptr(), fi) }, 0);
+        }
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn flock_conflicts_between_handles() {
+        let server = mount();
+        std::fs::write(server.root().join("fuse_flock"), b"x").unwrap();
+        let mut first = open("/fuse_flock", O_RDWR);
+        let mut second = open("/fuse_flock", O_RDONLY);
+        let flock = |fi: &mut fuse_file_info, op: u32| unsafe {
+            ifuse_flock(c("/fuse_flock").as_ptr(), fi, op as _)
+        };
+
+        assert_eq!(flock(&mut first, LOCK_SH), 0);
+        assert_eq!(flock(&mut second, LOCK_SH | LOCK_NB), 0);
+        assert_eq!(flock(&mut second, LOCK_EX | LOCK_NB), -EAGAIN);
+        assert_eq!(flock(&mut first, LOCK_UN), 0);
+        assert_eq!(flock(&mut second, LOCK_EX | LOCK_NB), 0);
+        assert_eq!(flock(&mut first, LOCK_EX | LOCK_NB), -EAGAIN);
+        assert_eq!(flock(&mut first, LOCK_NB), -EINVAL);
+
+        // a blocking request waits for the holder to let go
+        let fh = second.fh;
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            set_lock(fh, LockOp::Unlock, Duration::ZERO)
+        });
+        assert_eq!(flock(&mut first, LOCK_EX), 0);
+        assert_eq!(release.join().unwrap(), 0);
+
+        for fi in [&mut first, &mut second] {
+            assert_eq!(unsafe { ifuse_release(c("/fuse_flock").as_ptr(), fi) }, 0);
+        }
+    }
+
+    #[test]
+    fn rename_replaces_target() {
+        let server = mount();
+        std::fs::write(server.root().join("fuse_from"), b"new").unwrap();
+        std::fs::write(server.root().join("fuse_to"), b"old").unwrap();
+
+        let res = unsafe { ifuse_rename(c("/fuse_from").as_ptr(), c("/fuse_to").as_ptr()) };
+        assert_eq!(res, 0);
+        assert_eq!(
+            std::fs::read(server.root().join("fuse_to")).unwrap(),
+            b"new"
+        );
+        assert_eq!(getattr("/fuse_from").0, -ENOENT);
+        // the original moved aside is gone
+        assert!(!std::fs::read_dir(server.root()).unwrap().any(|entry| entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with(".fuse_to")));
+    }
+
+    #[test]
+    fn utimens_sets_the_device_mtime() {
+        let server = mount();
+        let file = server.root().join("fuse_utimens");
+        std::fs::write(&file, b"x").unwrap();
+        let times = |mtime: timespec| {
+            let atime = timespec {
+                tv_sec: 0,
+                tv_nsec: UTIME_OMIT as _,
+            };
+            unsafe { ifuse_utimens(c("/fuse_utimens").as_ptr(), [atime, mtime].as_ptr()) }
+        };
+
+        let mtime = timespec {
+            tv_sec: 1_500_000_000,
+            tv_nsec: 0,
+        };
+        assert_eq!(times(mtime), 0);
+        let modified = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        assert_eq!(
+            std::fs::metadata(&file).unwrap().modified().unwrap(),
+            modified
+        );
+        assert_eq!(getattr("/fuse_utimens").1.st_mtim.tv_sec, 1_500_000_000);
+
+        let omit = timespec {
+            tv_sec: 0,
+            tv_nsec: UTIME_OMIT as _,
+        };
+        assert_eq!(times(omit), 0);
+        assert_eq!(
+            std::fs::metadata(&file).unwrap().modified().unwrap(),
+            modified
+        );
+
+        let res = unsafe { ifuse_utimens(c("/fuse_utimens").as_ptr(), std::ptr::null()) };
+        assert_eq!(res, 0);
+        assert!(std::fs::metadata(&file).unwrap().modified().unwrap() > modified);
+        let res = unsafe { ifuse_utimens(c("/fuse_missing").as_ptr(), std::ptr::null()) };
+        assert_eq!(res, -ENOENT);
+    }
+
+    #[test]
+    fn failed_replacing_rename_keeps_target() {
+        VERBOSE.get_or_init(|| false);
+        let (server, client) = MockServer::start();
+        std::fs::write(server.root().join("to"), b"old").unwrap();
+
+        assert_eq!(
+            rename(&client, "/missing", "/to"),
+            Err(AfcError::ObjectNotFound)
+        );
+        assert_eq!(std::fs::read(server.root().join("to")).unwrap(), b"old");
+        let names: Vec<_> = std::fs::read_dir(server.root()).unwrap().collect();
+        assert_eq!(names.len(), 1);
+
+        std::fs::create_dir(server.root().join("dir")).unwrap();
+        assert_eq!(rename(&client, "/to", "/dir"), Err(AfcError::ObjectExists));
     }
 }
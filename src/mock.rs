@@ -0,0 +1,639 @@
+#![allow(non_upper_case_globals)]
+// shared by the tests of the library and of the binary, which use different parts
+#![allow(dead_code)]
+use crate::{
+    afc_file_mode_t_AFC_FOPEN_APPEND, afc_file_mode_t_AFC_FOPEN_RDAPPEND,
+    afc_file_mode_t_AFC_FOPEN_RDONLY, afc_file_mode_t_AFC_FOPEN_RW, afc_file_mode_t_AFC_FOPEN_WR,
+    afc_file_mode_t_AFC_FOPEN_WRONLY, afc_link_type_t, afc_link_type_t_AFC_HARDLINK,
+    afc_link_type_t_AFC_SYMLINK, afc_lock_op_t_AFC_LOCK_EX, afc_lock_op_t_AFC_LOCK_SH,
+    afc_lock_op_t_AFC_LOCK_UN, idevice_error_t_IDEVICE_E_SUCCESS,
+    protocol::{afc_opcode_t, t_afc_struct, AfcHeader, AFCMAGIC},
+    AfcError, Client, Connector, MemoryTransport, TcpTransport, Transport,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use num_traits::FromPrimitive;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    net::TcpListener,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+const HEADER_SIZE: usize = size_of::<AfcHeader>();
+
+static NEXT_ROOT: AtomicUsize = AtomicUsize::new(0);
+static NEXT_SESSION: AtomicUsize = AtomicUsize::new(0);
+
+/// Lock holders per file as (session, handle, exclusive), shared by all connections
+type Locks = Arc<Mutex<HashMap<PathBuf, Vec<(usize, u64, bool)>>>>;
+
+/// Misbehaviour applied to the next request the server receives
+#[derive(Debug, Clone, Copy)]
+pub enum Fault {
+    /// Closes the connection instead of answering
+    DropConnection,
+    /// Carries the request out, then closes the connection before answering
+    LoseReply,
+    /// Sleeps before answering
+    Delay(Duration),
+    /// Answers with a packet number the client did not send
+    WrongPacketNumber,
+    /// Holds the answer back until the next request has been answered
+    Reorder,
+}
+
+/// In-process AFC server backed by a temporary directory
+pub struct MockServer {
+    shared: Shared,
+}
+
+/// State all connections to one server see
+#[derive(Clone)]
+struct Shared {
+    root: PathBuf,
+    faults: Arc<Mutex<VecDeque<Fault>>>,
+    requests: Arc<Mutex<HashMap<u64, usize>>>,
+    locks: Locks,
+    denied: Arc<Mutex<Vec<PathBuf>>>,
+    unsupported: Arc<Mutex<Vec<u64>>>,
+}
+
+impl MockServer {
+    /// Starts a server and returns it with a `Client` connected over an in-memory pipe
+    pub fn start() -> (Self, Client) {
+        let (client_end, server_end) = MemoryTransport::pair();
+        let server = Self::spawn(move || Some(Box::new(server_end) as Box<dyn Transport>));
+        (server, Client::with_transport(client_end))
+    }
+
+    /// Starts a server and returns it with a `Client` connected over loopback TCP
+    pub fn start_tcp() -> (Self, Client) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Self::spawn(move || {
+            let (stream, _) = listener.accept().ok()?;
+            Some(Box::new(TcpTransport::from(stream)) as Box<dyn Transport>)
+        });
+        let client = Client::with_transport(TcpTransport::connect(addr).unwrap());
+        (server, client)
+    }
+
+    /// Opens another connection to the same file system, with handles of its own
+    pub fn connect(&self) -> Client {
+        Client::with_transport(self.shared.connect())
+    }
+
+    /// Connector for `Client::reconnect_with` opening new connections to this server
+    pub fn connector(&self) -> Connector {
+        let shared = self.shared.clone();
+        Box::new(move || Some(Box::new(shared.connect()) as Box<dyn Transport>))
+    }
+
+    fn spawn<F>(accept: F) -> Self
+    where
+        F: FnOnce() -> Option<Box<dyn Transport>> + Send + 'static,
+    {
+        let root = std::env::temp_dir().join(format!(
+            "ifuse-mock-{}-{}",
+            std::process::id(),
+            NEXT_ROOT.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&root).unwrap();
+
+        let server = Self {
+            shared: Shared {
+                root,
+                faults: Arc::new(Mutex::new(VecDeque::new())),
+                requests: Arc::new(Mutex::new(HashMap::new())),
+                locks: Arc::new(Mutex::new(HashMap::new())),
+                denied: Arc::new(Mutex::new(Vec::new())),
+                unsupported: Arc::new(Mutex::new(Vec::new())),
+            },
+        };
+        server.shared.serve(accept);
+        server
+    }
+
+    /// Directory the device file system is served from
+    pub fn root(&self) -> &Path {
+        &self.shared.root
+    }
+
+    /// Number of requests received so far with the given operation, on all connections
+    pub fn request_count(&self, operation: afc_opcode_t) -> usize {
+        let requests = self.shared.requests.lock().unwrap();
+        requests.get(&(operation as u64)).copied().unwrap_or(0)
+    }
+
+    /// Queues a fault for the next request not already claimed by an earlier fault
+    pub fn inject(&self, fault: Fault) {
+        self.shared.faults.lock().unwrap().push_back(fault);
+    }
+
+    /// Refuses listing the directory at `path`, as the device does for system directories
+    pub fn deny(&self, path: &str) {
+        let path = self.shared.root.join(path.trim_start_matches('/'));
+        self.shared.denied.lock().unwrap().push(path);
+    }
+
+    /// Answers `operation` with UnknownPacketType, as older devices do
+    pub fn unsupported(&self, operation: afc_opcode_t) {
+        self.shared
+            .unsupported
+            .lock()
+            .unwrap()
+            .push(operation as u64);
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.shared.root);
+    }
+}
+
+impl Shared {
+    fn connect(&self) -> MemoryTransport {
+        let (client_end, server_end) = MemoryTransport::pair();
+        self.serve(move || Some(Box::new(server_end) as Box<dyn Transport>));
+        client_end
+    }
+
+    fn serve<F>(&self, accept: F)
+    where
+        F: FnOnce() -> Option<Box<dyn Transport>> + Send + 'static,
+    {
+        let mut session = Session {
+            id: NEXT_SESSION.fetch_add(1, Ordering::SeqCst),
+            root: self.root.clone(),
+            faults: self.faults.clone(),
+            requests: self.requests.clone(),
+            locks: self.locks.clone(),
+            denied: self.denied.clone(),
+            unsupported: self.unsupported.clone(),
+            handles: HashMap::new(),
+            paths: HashMap::new(),
+            // like the device's, handles do not have to fit in 32 bits
+            next_handle: 0x1_0000_0000,
+        };
+        // the session ends by itself once the client hangs up
+        std::thread::spawn(move || {
+            if let Some(mut transport) = accept() {
+                session.serve(transport.as_mut());
+            }
+            // like the device, a lost connection gives up its locks
+            for holders in session.locks.lock().unwrap().values_mut() {
+                holders.retain(|(id, _, _)| *id != session.id);
+            }
+        });
+    }
+}
+
+struct Session {
+    id: usize,
+    root: PathBuf,
+    faults: Arc<Mutex<VecDeque<Fault>>>,
+    requests: Arc<Mutex<HashMap<u64, usize>>>,
+    locks: Locks,
+    denied: Arc<Mutex<Vec<PathBuf>>>,
+    unsupported: Arc<Mutex<Vec<u64>>>,
+    handles: HashMap<u64, File>,
+    paths: HashMap<u64, PathBuf>,
+    next_handle: u64,
+}
+
+enum Reply {
+    /// STATUS 0
+    Ok,
+    Status(AfcError),
+    Data(Vec<u8>),
+    Number(afc_opcode_t, u64),
+}
+
+impl Session {
+    fn serve(&mut self, transport: &mut dyn Transport) {
+        let mut held: Option<Vec<u8>> = None;
+        loop {
+            let mut header = [0u8; HEADER_SIZE];
+            if transport.receive_timeout(&mut header, 0).0 != idevice_error_t_IDEVICE_E_SUCCESS {
+                return;
+            }
+            if &header[0..8] != AFCMAGIC {
+                return;
+            }
+            let entire_length = LittleEndian::read_u64(&header[8..16]) as usize;
+            let packet_num = LittleEndian::read_u64(&header[24..32]);
+            let operation = LittleEndian::read_u64(&header[32..40]);
+
+            let mut payload = vec![0u8; entire_length.saturating_sub(HEADER_SIZE)];
+            if !payload.is_empty()
+                && transport.receive_timeout(&mut payload, 0).0 != idevice_error_t_IDEVICE_E_SUCCESS
+            {
+                return;
+            }
+
+            *self.requests.lock().unwrap().entry(operation).or_default() += 1;
+
+            let mut packet_num = packet_num;
+            let mut hold = false;
+            let mut lose = false;
+            match self.faults.lock().unwrap().pop_front() {
+                Some(Fault::DropConnection) => {
+                    transport.disconnect();
+                    return;
+                }
+                Some(Fault::Delay(delay)) => std::thread::sleep(delay),
+                Some(Fault::WrongPacketNumber) => packet_num = packet_num.wrapping_add(1000),
+                Some(Fault::Reorder) => hold = true,
+                Some(Fault::LoseReply) => lose = true,
+                None => {}
+            }
+
+            let known = !self.unsupported.lock().unwrap().contains(&operation);
+            let reply = match afc_opcode_t::from_u64(operation).filter(|_| known) {
+                Some(operation) => self.handle(operation, &payload),
+                None => Reply::Status(AfcError::UnknownPacketType),
+            };
+            if lose {
+                transport.disconnect();
+                return;
+            }
+
+            let (operation, this_data, data) = match reply {
+                Reply::Ok => (afc_opcode_t::STATUS, 8, 0u64.to_le_bytes().to_vec()),
+                Reply::Status(status) => (
+                    afc_opcode_t::STATUS,
+                    8,
+                    (status as u64).to_le_bytes().to_vec(),
+                ),
+                Reply::Number(operation, value) => (operation, 8, value.to_le_bytes().to_vec()),
+                // like the device, data is sent as payload after a bare header
+                Reply::Data(data) => (afc_opcode_t::DATA, 0, data),
+            };
+            let response = AfcHeader {
+                magic: *AFCMAGIC,
+                entire_length: (HEADER_SIZE + data.len()) as u64,
+                this_length: (HEADER_SIZE + this_data) as u64,
+                packet_num,
+                operation: operation as u64,
+            };
+            let mut packet = response.to_bytes();
+            packet.extend(data);
+            if hold {
+                held = Some(packet);
+                continue;
+            }
+            if transport.send(&packet).0 != idevice_error_t_IDEVICE_E_SUCCESS {
+                return;
+            }
+            if let Some(packet) = held.take() {
+                if transport.send(&packet).0 != idevice_error_t_IDEVICE_E_SUCCESS {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, operation: afc_opcode_t, payload: &[u8]) -> Reply {
+        let result = match operation {
+            afc_opcode_t::READ_DIR => self.read_dir(payload),
+            afc_opcode_t::GET_FILE_INFO => self.file_info(payload),
+            afc_opcode_t::GET_DEVINFO => Ok(Reply::Data(strings(&[
+                ("Model", "iPhone0,0".to_string()),
+                ("FSTotalBytes", "64000000000".to_string()),
+                ("FSFreeBytes", "32000000000".to_string()),
+                ("FSBlockSize", "4096".to_string()),
+            ]))),
+            afc_opcode_t::FILE_OPEN => self.file_open(payload),
+            afc_opcode_t::READ => self.file_read(payload),
+            afc_opcode_t::WRITE => self.file_write(payload),
+            afc_opcode_t::FILE_SEEK => self.file_seek(payload),
+            afc_opcode_t::FILE_TELL => self
+                .file(payload)
+                .and_then(|file| file.stream_position().map_err(io_error))
+                .map(|pos| Reply::Number(afc_opcode_t::FILE_TELL_RES, pos)),
+            afc_opcode_t::FILE_SET_SIZE => {
+                let size = u64_at(payload, 8);
+                self.file(payload)
+                    .and_then(|file| file.set_len(size).map_err(io_error))
+                    .map(|_| Reply::Ok)
+            }
+            afc_opcode_t::FILE_LOCK => self.file_lock(payload),
+            afc_opcode_t::FILE_CLOSE => {
+                let handle = u64_at(payload, 0);
+                self.unlock(handle);
+                self.paths.remove(&handle);
+                match self.handles.remove(&handle) {
+                    Some(_) => Ok(Reply::Ok),
+                    None => Err(AfcError::InvalidArg),
+                }
+            }
+            afc_opcode_t::MAKE_DIR => self
+                .path(payload)
+                .and_then(|path| std::fs::create_dir_all(path).map_err(io_error))
+                .map(|_| Reply::Ok),
+            afc_opcode_t::TRUNCATE => self
+                .path(payload.get(8..).unwrap_or_default())
+                .and_then(|path| OpenOptions::new().write(true).open(path).map_err(io_error))
+                .and_then(|file| file.set_len(u64_at(payload, 0)).map_err(io_error))
+                .map(|_| Reply::Ok),
+            afc_opcode_t::REMOVE_PATH => self.remove(payload),
+            afc_opcode_t::REMOVE_PATH_AND_CONTENTS => self.remove_all(payload),
+            afc_opcode_t::MAKE_LINK => self.make_link(payload),
+            afc_opcode_t::RENAME_PATH => self.rename(payload),
+            afc_opcode_t::SET_FILE_TIME => self
+                .path(payload.get(8..).unwrap_or_default())
+                .and_then(|path| File::open(path).map_err(io_error))
+                .and_then(|file| {
+                    let mtime = UNIX_EPOCH + Duration::from_nanos(u64_at(payload, 0));
+                    file.set_modified(mtime).map_err(io_error)
+                })
+                .map(|_| Reply::Ok),
+            _ => Err(AfcError::UnknownPacketType),
+        };
+
+        result.unwrap_or_else(Reply::Status)
+    }
+
+    fn read_dir(&self, payload: &[u8]) -> Result<Reply, AfcError> {
+        let path = self.path(payload)?;
+        if self.denied.lock().unwrap().contains(&path) {
+            return Err(AfcError::PermDenied);
+        }
+        let mut names = vec![".".to_string(), "..".to_string()];
+        for entry in std::fs::read_dir(path).map_err(io_error)? {
+            names.push(
+                entry
+                    .map_err(io_error)?
+                    .file_name()
+                    .to_string_lossy()
+                    .to_string(),
+            );
+        }
+
+        let mut data = Vec::new();
+        for name in names {
+            data.extend(name.as_bytes());
+            data.push(0);
+        }
+        Ok(Reply::Data(data))
+    }
+
+    fn file_info(&self, payload: &[u8]) -> Result<Reply, AfcError> {
+        let path = self.path(payload)?;
+        let meta = std::fs::symlink_metadata(&path).map_err(io_error)?;
+        let file_type = meta.file_type();
+        let ifmt = if file_type.is_symlink() {
+            "S_IFLNK"
+        } else if file_type.is_dir() {
+            "S_IFDIR"
+        } else {
+            "S_IFREG"
+        };
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        #[cfg(unix)]
+        let nlink = std::os::unix::fs::MetadataExt::nlink(&meta);
+        #[cfg(not(unix))]
+        let nlink = 1;
+
+        let mut info = vec![
+            ("st_size", meta.len().to_string()),
+            ("st_blocks", meta.len().div_ceil(512).to_string()),
+            ("st_nlink", nlink.to_string()),
+            ("st_ifmt", ifmt.to_string()),
+            ("st_mtime", mtime.to_string()),
+            ("st_birthtime", mtime.to_string()),
+        ];
+        if file_type.is_symlink() {
+            let target = std::fs::read_link(&path).map_err(io_error)?;
+            info.push(("LinkTarget", target.to_string_lossy().to_string()));
+        }
+        Ok(Reply::Data(strings(&info)))
+    }
+
+    fn file_open(&mut self, payload: &[u8]) -> Result<Reply, AfcError> {
+        let mode = u64_at(payload, 0);
+        let path = self.path(&payload[8..])?;
+
+        let mut options = OpenOptions::new();
+        match mode {
+            afc_file_mode_t_AFC_FOPEN_RDONLY => options.read(true),
+            afc_file_mode_t_AFC_FOPEN_RW => options.read(true).write(true).create(true),
+            afc_file_mode_t_AFC_FOPEN_WRONLY => options.write(true).create(true).truncate(true),
+            afc_file_mode_t_AFC_FOPEN_WR => {
+                options.read(true).write(true).create(true).truncate(true)
+            }
+            afc_file_mode_t_AFC_FOPEN_APPEND => options.append(true).create(true),
+            afc_file_mode_t_AFC_FOPEN_RDAPPEND => options.read(true).append(true).create(true),
+            _ => return Err(AfcError::InvalidArg),
+        };
+        let file = options.open(&path).map_err(io_error)?;
+
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(handle, file);
+        self.paths.insert(handle, path);
+        Ok(Reply::Number(afc_opcode_t::FILE_OPEN_RES, handle))
+    }
+
+    fn file_lock(&mut self, payload: &[u8]) -> Result<Reply, AfcError> {
+        let handle = u64_at(payload, 0);
+        let exclusive = match u64_at(payload, 8) as u32 {
+            afc_lock_op_t_AFC_LOCK_SH => false,
+            afc_lock_op_t_AFC_LOCK_EX => true,
+            afc_lock_op_t_AFC_LOCK_UN => {
+                self.unlock(handle);
+                return Ok(Reply::Ok);
+            }
+            _ => return Err(AfcError::InvalidArg),
+        };
+        let path = self.paths.get(&handle).ok_or(AfcError::InvalidArg)?;
+
+        let mut locks = self.locks.lock().unwrap();
+        let holders = locks.entry(path.clone()).or_default();
+        let me = (self.id, handle);
+        let conflict = holders
+            .iter()
+            .filter(|(id, h, _)| (*id, *h) != me)
+            .any(|(_, _, held)| exclusive || *held);
+        if conflict {
+            return Err(AfcError::OpWouldBlock);
+        }
+        holders.retain(|(id, h, _)| (*id, *h) != me);
+        holders.push((self.id, handle, exclusive));
+        Ok(Reply::Ok)
+    }
+
+    fn unlock(&self, handle: u64) {
+        if let Some(path) = self.paths.get(&handle) {
+            if let Some(holders) = self.locks.lock().unwrap().get_mut(path) {
+                holders.retain(|(id, h, _)| (*id, *h) != (self.id, handle));
+            }
+        }
+    }
+
+    fn file_read(&mut self, payload: &[u8]) -> Result<Reply, AfcError> {
+        let size = u64_at(payload, 8) as usize;
+        let file = self.file(payload)?;
+        let mut data = Vec::with_capacity(size);
+        Read::take(file, size as u64)
+            .read_to_end(&mut data)
+            .map_err(io_error)?;
+        Ok(Reply::Data(data))
+    }
+
+    fn file_write(&mut self, payload: &[u8]) -> Result<Reply, AfcError> {
+        let file = self.file(payload)?;
+        file.write_all(payload.get(8..).unwrap_or_default())
+            .map_err(io_error)?;
+        Ok(Reply::Ok)
+    }
+
+    fn file_seek(&mut self, payload: &[u8]) -> Result<Reply, AfcError> {
+        let whence = u64_at(payload, 8);
+        let offset = u64_at(payload, 16) as i64;
+        let pos = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(AfcError::InvalidArg),
+        };
+        self.file(payload)?.seek(pos).map_err(io_error)?;
+        Ok(Reply::Ok)
+    }
+
+    fn remove(&self, payload: &[u8]) -> Result<Reply, AfcError> {
+        let path = self.path(payload)?;
+        let meta = std::fs::symlink_metadata(&path).map_err(io_error)?;
+        if meta.is_dir() {
+            if std::fs::read_dir(&path).map_err(io_error)?.next().is_some() {
+                return Err(AfcError::DirNotEmpty);
+            }
+            std::fs::remove_dir(path).map_err(io_error)?;
+        } else {
+            std::fs::remove_file(path).map_err(io_error)?;
+        }
+        Ok(Reply::Ok)
+    }
+
+    fn remove_all(&self, payload: &[u8]) -> Result<Reply, AfcError> {
+        let path = self.path(payload)?;
+        if path == self.root {
+            return Err(AfcError::PermDenied);
+        }
+        let meta = std::fs::symlink_metadata(&path).map_err(io_error)?;
+        if meta.is_dir() {
+            std::fs::remove_dir_all(path).map_err(io_error)?;
+        } else {
+            std::fs::remove_file(path).map_err(io_error)?;
+        }
+        Ok(Reply::Ok)
+    }
+
+    /// Symlink targets are stored as sent, hard link targets are device paths
+    fn make_link(&self, payload: &[u8]) -> Result<Reply, AfcError> {
+        let (target, link) = split_paths(payload.get(8..).unwrap_or_default());
+        let link = self.resolve(link)?;
+        if std::fs::symlink_metadata(&link).is_ok() {
+            return Err(AfcError::ObjectExists);
+        }
+        match u64_at(payload, 0) as afc_link_type_t {
+            afc_link_type_t_AFC_HARDLINK => {
+                std::fs::hard_link(self.resolve(target)?, link).map_err(io_error)?
+            }
+            #[cfg(unix)]
+            afc_link_type_t_AFC_SYMLINK => {
+                std::os::unix::fs::symlink(target, link).map_err(io_error)?
+            }
+            _ => return Err(AfcError::OpNotSupported),
+        }
+        Ok(Reply::Ok)
+    }
+
+    fn rename(&self, payload: &[u8]) -> Result<Reply, AfcError> {
+        let (from, to) = split_paths(payload);
+        let to = self.resolve(to)?;
+        // unlike rename(2), the device does not replace what is there
+        if std::fs::symlink_metadata(&to).is_ok() {
+            return Err(AfcError::ObjectExists);
+        }
+        std::fs::rename(self.resolve(from)?, to).map_err(io_error)?;
+        Ok(Reply::Ok)
+    }
+
+    fn file(&mut self, payload: &[u8]) -> Result<&mut File, AfcError> {
+        self.handles
+            .get_mut(&u64_at(payload, 0))
+            .ok_or(AfcError::InvalidArg)
+    }
+
+    fn path(&self, payload: &[u8]) -> Result<PathBuf, AfcError> {
+        self.resolve(split_paths(payload).0)
+    }
+
+    /// Maps a device path onto the served directory, refusing to leave it
+    fn resolve(&self, path: &str) -> Result<PathBuf, AfcError> {
+        let mut resolved = self.root.clone();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(part) => resolved.push(part),
+                Component::ParentDir => {
+                    if resolved == self.root {
+                        return Err(AfcError::PermDenied);
+                    }
+                    resolved.pop();
+                }
+                _ => {}
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+fn u64_at(payload: &[u8], offset: usize) -> u64 {
+    payload
+        .get(offset..offset + 8)
+        .map(LittleEndian::read_u64)
+        .unwrap_or(0)
+}
+
+/// First two null-terminated strings of a payload
+fn split_paths(payload: &[u8]) -> (&str, &str) {
+    let mut parts = payload
+        .split(|&b| b == 0)
+        .map(|p| std::str::from_utf8(p).unwrap_or(""));
+    (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+}
+
+fn strings(pairs: &[(&str, String)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (key, value) in pairs {
+        data.extend(key.as_bytes());
+        data.push(0);
+        data.extend(value.as_bytes());
+        data.push(0);
+    }
+    data
+}
+
+fn io_error(e: std::io::Error) -> AfcError {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => AfcError::ObjectNotFound,
+        std::io::ErrorKind::PermissionDenied => AfcError::PermDenied,
+        std::io::ErrorKind::AlreadyExists => AfcError::ObjectExists,
+        std::io::ErrorKind::IsADirectory => AfcError::ObjectIsDir,
+        std::io::ErrorKind::DirectoryNotEmpty => AfcError::DirNotEmpty,
+        _ => AfcError::IoError,
+    }
+}
@@ -0,0 +1,61 @@
+use ifuse::Client;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Low bits of a FUSE file handle holding the AFC handle, the bits above name the connection
+const HANDLE_BITS: u32 = 48;
+
+/// AFC connections shared by the FUSE threads.
+///
+/// Requests on paths go to an idle connection. AFC file handles only exist on the
+/// connection that opened them, so the FUSE file handle records which one that was.
+pub struct Pool {
+    clients: Vec<Client>,
+    next: AtomicUsize,
+}
+
+impl Pool {
+    pub fn new(clients: Vec<Client>) -> Self {
+        assert!(!clients.is_empty(), "a pool needs at least one connection");
+        Self {
+            clients,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn clients(&self) -> &[Client] {
+        &self.clients
+    }
+
+    /// A connection for a request that is not tied to an open file
+    pub fn client(&self) -> &Client {
+        self.pick().1
+    }
+
+    /// An idle connection if there is one, otherwise the next in turn
+    pub fn pick(&self) -> (usize, &Client) {
+        let len = self.clients.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+        let index = (0..len)
+            .map(|i| (start + i) % len)
+            .find(|&i| !self.clients[i].is_busy())
+            .unwrap_or(start);
+        (index, &self.clients[index])
+    }
+
+    /// FUSE file handle for an AFC handle opened on connection `index`. `Client`
+    /// numbers handles from 1 on, so they stay far below the bits of the index
+    pub fn bind(index: usize, handle: u64) -> u64 {
+        debug_assert!(
+            handle < 1 << HANDLE_BITS,
+            "handle {handle} overflows into the index"
+        );
+        debug_assert!((index as u64) < 1 << (u64::BITS - HANDLE_BITS));
+        ((index as u64) << HANDLE_BITS) | handle
+    }
+
+    /// Connection and AFC handle behind a FUSE file handle
+    pub fn file(&self, fh: u64) -> (&Client, u64) {
+        let index = (fh >> HANDLE_BITS) as usize;
+        (&self.clients[index], fh & ((1 << HANDLE_BITS) - 1))
+    }
+}
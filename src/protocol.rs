@@ -0,0 +1,261 @@
+//! Wire format of AFC: packet header, operation codes and request payloads
+#![allow(non_camel_case_types, dead_code)]
+#![allow(clippy::upper_case_acronyms)]
+use crate::afc_file_mode_t;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use num_derive::{FromPrimitive, ToPrimitive};
+
+pub const AFCMAGIC: &[u8; 8] = b"CFA6LPAA";
+
+#[derive(Copy, Clone, Debug, FromPrimitive, ToPrimitive, PartialEq, PartialOrd)]
+pub enum afc_opcode_t {
+    STATUS = 0x00000001,
+    DATA = 0x00000002,                     // Data */
+    READ_DIR = 0x00000003,                 // ReadDir */
+    READ_FILE = 0x00000004,                // ReadFile */
+    WRITE_FILE = 0x00000005,               // WriteFile */
+    WRITE_PART = 0x00000006,               // WritePart */
+    TRUNCATE = 0x00000007,                 // TruncateFile */
+    REMOVE_PATH = 0x00000008,              // RemovePath */
+    MAKE_DIR = 0x00000009,                 // MakeDir */
+    GET_FILE_INFO = 0x0000000a,            // GetFileInfo */
+    GET_DEVINFO = 0x0000000b,              // GetDeviceInfo */
+    WRITE_FILE_ATOM = 0x0000000c,          // WriteFileAtomic (tmp file+rename) */
+    FILE_OPEN = 0x0000000d,                // FileRefOpen */
+    FILE_OPEN_RES = 0x0000000e,            // FileRefOpenResult */
+    READ = 0x0000000f,                     // FileRefRead */
+    WRITE = 0x00000010,                    // FileRefWrite */
+    FILE_SEEK = 0x00000011,                // FileRefSeek */
+    FILE_TELL = 0x00000012,                // FileRefTell */
+    FILE_TELL_RES = 0x00000013,            // FileRefTellResult */
+    FILE_CLOSE = 0x00000014,               // FileRefClose */
+    FILE_SET_SIZE = 0x00000015,            // FileRefSetFileSize (ftruncate) */
+    GET_CON_INFO = 0x00000016,             // GetConnectionInfo */
+    SET_CON_OPTIONS = 0x00000017,          // SetConnectionOptions */
+    RENAME_PATH = 0x00000018,              // RenamePath */
+    SET_FS_BS = 0x00000019,                // SetFSBlockSize (0x800000) */
+    SET_SOCKET_BS = 0x0000001A,            // SetSocketBlockSize (0x800000) */
+    FILE_LOCK = 0x0000001B,                // FileRefLock */
+    MAKE_LINK = 0x0000001C,                // MakeLink */
+    SET_FILE_TIME = 0x0000001E,            // set st_mtime */
+    REMOVE_PATH_AND_CONTENTS = 0x00000022, /* RemovePathAndContents */
+}
+
+pub trait t_afc_struct {
+    fn to_bytes(&self) -> Vec<u8>;
+}
+
+pub struct afc_stat_t {
+    pub filename: Vec<u8>,
+}
+impl t_afc_struct for afc_stat_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.filename.clone()
+    }
+}
+pub struct afc_readdir_t {
+    pub filename: Vec<u8>,
+}
+impl t_afc_struct for afc_readdir_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.filename.clone()
+    }
+}
+
+pub struct afc_fopen_t {
+    pub mode: afc_file_mode_t,
+    pub filename: Vec<u8>,
+}
+impl t_afc_struct for afc_fopen_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.mode.to_le_bytes().to_vec();
+        buf.extend(&self.filename);
+        buf
+    }
+}
+
+pub struct afc_fread_t {
+    pub handle: u64,
+    pub size: u64,
+}
+impl t_afc_struct for afc_fread_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.handle.to_le_bytes().to_vec();
+        buf.extend_from_slice(&self.size.to_le_bytes());
+        buf
+    }
+}
+
+pub struct afc_fwrite_t {
+    pub handle: u64,
+    pub data: Vec<u8>,
+}
+impl t_afc_struct for afc_fwrite_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.handle.to_le_bytes().to_vec();
+        buf.extend(&self.data);
+        buf
+    }
+}
+
+pub struct afc_fclose_t {
+    pub handle: u64,
+}
+impl t_afc_struct for afc_fclose_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.handle.to_le_bytes().to_vec()
+    }
+}
+
+pub struct afc_ftell_t {
+    pub handle: u64,
+}
+impl t_afc_struct for afc_ftell_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.handle.to_le_bytes().to_vec()
+    }
+}
+
+pub struct afc_fset_size_t {
+    pub handle: u64,
+    pub newsize: u64,
+}
+impl t_afc_struct for afc_fset_size_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.handle.to_le_bytes().to_vec();
+        buf.extend_from_slice(&self.newsize.to_le_bytes());
+        buf
+    }
+}
+
+pub struct afc_lock_t {
+    pub handle: u64,
+    pub op: u64,
+}
+impl t_afc_struct for afc_lock_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.handle.to_le_bytes().to_vec();
+        buf.extend_from_slice(&self.op.to_le_bytes());
+        buf
+    }
+}
+
+pub struct afc_seek_t {
+    pub handle: u64,
+    pub whence: u64,
+    pub offset: i64,
+}
+impl t_afc_struct for afc_seek_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.handle.to_le_bytes().to_vec();
+        buf.extend_from_slice(&self.whence.to_le_bytes());
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        buf
+    }
+}
+
+pub struct afc_truncate_t {
+    pub filename: Vec<u8>,
+    pub newsize: u64,
+}
+impl t_afc_struct for afc_truncate_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.newsize.to_le_bytes().to_vec();
+        buf.extend_from_slice(&self.filename);
+        buf
+    }
+}
+
+pub struct afc_rm_t {
+    pub filename: Vec<u8>,
+}
+impl t_afc_struct for afc_rm_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.filename.clone()
+    }
+}
+
+pub struct afc_mkdir_t {
+    pub filename: Vec<u8>,
+}
+impl t_afc_struct for afc_mkdir_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.filename.clone()
+    }
+}
+
+pub struct afc_rename_t {
+    pub from: Vec<u8>,
+    pub to: Vec<u8>,
+}
+impl t_afc_struct for afc_rename_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.from.clone();
+        buf.extend(&self.to);
+        buf
+    }
+}
+
+pub struct afc_set_file_time_t {
+    pub mtime: u64,
+    pub filename: Vec<u8>,
+}
+impl t_afc_struct for afc_set_file_time_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.mtime.to_le_bytes().to_vec();
+        buf.extend(&self.filename);
+        buf
+    }
+}
+
+pub struct afc_link_t {
+    pub link_type: u64,
+    pub target: Vec<u8>,
+    pub link_name: Vec<u8>,
+}
+impl t_afc_struct for afc_link_t {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.link_type.to_le_bytes().to_vec();
+        buf.extend(&self.target);
+        buf.extend(&self.link_name);
+        buf
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AfcHeader {
+    pub magic: [u8; 8],
+    pub entire_length: u64,
+    pub this_length: u64,
+    pub packet_num: u64,
+    pub operation: u64,
+}
+
+impl t_afc_struct for AfcHeader {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(size_of::<AfcHeader>());
+        buf.extend_from_slice(&self.magic);
+        buf.write_u64::<LittleEndian>(self.entire_length).unwrap();
+        buf.write_u64::<LittleEndian>(self.this_length).unwrap();
+        buf.write_u64::<LittleEndian>(self.packet_num).unwrap();
+        buf.write_u64::<LittleEndian>(self.operation).unwrap();
+        buf
+    }
+}
+
+pub fn parse_header(data: &[u8]) -> Option<AfcHeader> {
+    if data.len() < std::mem::size_of::<AfcHeader>() {
+        return None;
+    }
+
+    let mut header = AfcHeader {
+        magic: [0; 8],
+        entire_length: LittleEndian::read_u64(&data[8..16]),
+        this_length: LittleEndian::read_u64(&data[16..24]),
+        packet_num: LittleEndian::read_u64(&data[24..32]),
+        operation: LittleEndian::read_u64(&data[32..40]),
+    };
+    header.magic.copy_from_slice(&data[0..8]);
+    Some(header)
+}
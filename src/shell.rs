@@ -0,0 +1,400 @@
+use crate::{commands, idevice_t, open_service};
+use ifuse::{AfcError, Client};
+use rustyline::{
+    completion::{Completer, Pair},
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    validate::Validator,
+    Context, Editor, Helper,
+};
+use std::path::Path;
+
+/// Shell commands with their usage
+const COMMANDS: &[(&str, &str)] = &[
+    ("cd", "cd [path]"),
+    ("pwd", "pwd"),
+    ("ls", "ls [-l] [-R] [path]"),
+    ("stat", "stat <path>..."),
+    ("cat", "cat <path>"),
+    ("get", "get [-r] <path> [local]"),
+    ("put", "put [-r] <local> [path]"),
+    ("rm", "rm [-r] <path>..."),
+    ("mkdir", "mkdir <path>..."),
+    ("mv", "mv <from> <to>"),
+    ("df", "df"),
+    ("target", "target [media | <appid>]"),
+    ("help", "help"),
+    ("exit", "exit"),
+];
+
+/// A session of the shell, connected to the media directory or to an app's documents
+struct Shell {
+    device: idevice_t,
+    client: Client,
+    app_id: Option<String>,
+    cwd: String,
+}
+
+/// Runs the shell on `device` until `exit` or end of input, starting in the documents
+/// of `app_id` if given
+pub(crate) fn run(device: idevice_t, app_id: Option<&str>) -> Result<(), AfcError> {
+    let shell = Shell::connect(device, app_id).ok_or(AfcError::ServiceNotConnected)?;
+    let mut editor = Editor::new().map_err(|e| {
+        eprintln!("Cannot start the shell: {}", e);
+        AfcError::IoError
+    })?;
+    editor.set_helper(Some(shell));
+
+    loop {
+        let prompt = editor.helper().unwrap().prompt();
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("{}", e);
+                break;
+            }
+        };
+        let words = split_words(&line);
+        if words.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+        if !editor.helper_mut().unwrap().execute(&words) {
+            break;
+        }
+    }
+
+    if let Some(shell) = editor.helper() {
+        shell.client.close();
+    }
+    Ok(())
+}
+
+impl Shell {
+    fn connect(device: idevice_t, app_id: Option<&str>) -> Option<Shell> {
+        let client = open_service(device, app_id)?;
+        Some(Shell {
+            device,
+            client,
+            app_id: app_id.map(str::to_string),
+            cwd: root(app_id).to_string(),
+        })
+    }
+
+    fn prompt(&self) -> String {
+        format!(
+            "{}:{}> ",
+            self.app_id.as_deref().unwrap_or("media"),
+            self.cwd
+        )
+    }
+
+    /// Runs one command line, false once the shell should end
+    fn execute(&mut self, words: &[String]) -> bool {
+        let (name, args) = words.split_first().unwrap();
+        let (flags, args) = options(args);
+        let client = &self.client;
+        let path = |arg: Option<&&str>| resolve(&self.cwd, arg.copied().unwrap_or("."));
+        let stdout = &mut std::io::stdout().lock();
+
+        // failed requests have been reported already
+        match (name.as_str(), args.as_slice()) {
+            ("cd", [] | [_]) => {
+                let target = match args.first() {
+                    Some(arg) => resolve(&self.cwd, arg),
+                    None => root(self.app_id.as_deref()).to_string(),
+                };
+                match client.stat(&target) {
+                    Ok(info) if info.is_dir() => self.cwd = target,
+                    Ok(_) => eprintln!("{}: not a directory", target),
+                    Err(status) => eprintln!("{}: {}", target, status),
+                }
+            }
+            ("pwd", []) => println!("{}", self.cwd),
+            ("ls", [] | [_]) => {
+                let long = flags.contains('l');
+                let _ = commands::ls(
+                    client,
+                    &path(args.first()),
+                    long,
+                    flags.contains('R'),
+                    stdout,
+                );
+            }
+            ("stat", [_, ..]) => {
+                for arg in &args {
+                    let _ = commands::stat_path(client, &resolve(&self.cwd, arg), stdout);
+                }
+            }
+            ("cat", [arg]) => {
+                let _ = commands::cat(client, &resolve(&self.cwd, arg), stdout);
+            }
+            ("get", [remote] | [remote, _]) => {
+                let local = Path::new(args.get(1).copied().unwrap_or("."));
+                let remote = resolve(&self.cwd, remote);
+                let _ = commands::pull(client, &remote, local, flags.contains('r'));
+            }
+            ("put", [local] | [local, _]) => {
+                let remote = path(args.get(1));
+                let _ = commands::push(client, Path::new(local), &remote, flags.contains('r'));
+            }
+            ("rm", [_, ..]) => {
+                for arg in &args {
+                    let _ = commands::rm(client, &resolve(&self.cwd, arg), flags.contains('r'));
+                }
+            }
+            ("mkdir", [_, ..]) => {
+                for arg in &args {
+                    let _ = commands::mkdir(client, &resolve(&self.cwd, arg));
+                }
+            }
+            ("mv", [from, to]) => {
+                let _ = commands::mv(client, &resolve(&self.cwd, from), &resolve(&self.cwd, to));
+            }
+            ("df", []) => self.df(),
+            ("target", []) => println!("{}", self.app_id.as_deref().unwrap_or("media")),
+            ("target", [target]) => {
+                let app_id = Some(*target).filter(|target| *target != "media");
+                match Shell::connect(self.device, app_id) {
+                    Some(shell) => {
+                        self.client.close();
+                        *self = shell;
+                    }
+                    None => eprintln!("Cannot connect to {}", target),
+                }
+            }
+            ("help", []) => {
+                for (_, usage) in COMMANDS {
+                    println!("  {}", usage);
+                }
+            }
+            ("exit" | "quit", []) => return false,
+            (name, _) => match COMMANDS.iter().find(|(command, _)| *command == name) {
+                Some((_, usage)) => eprintln!("usage: {}", usage),
+                None => eprintln!("{}: unknown command, try help", name),
+            },
+        }
+        true
+    }
+
+    /// Prints the size and use of the device's storage
+    fn df(&self) {
+        let info = match self.client.device_info() {
+            Ok(info) => info,
+            Err(status) => return eprintln!("df: {}", status),
+        };
+        let (total, free) = (info.total_bytes, info.free_bytes);
+        let used = total.saturating_sub(free);
+        println!("{:>8} {:>8} {:>8} {:>5}", "Size", "Used", "Avail", "Use%");
+        println!(
+            "{:>8} {:>8} {:>8} {:>4}%",
+            human(total),
+            human(used),
+            human(free),
+            (used * 100).checked_div(total).unwrap_or(0)
+        );
+    }
+
+    /// Commands, or entries of the directory being typed, that start with `word`
+    fn candidates(&self, word: &str, command: bool) -> Vec<Pair> {
+        if command {
+            return COMMANDS
+                .iter()
+                .filter(|(name, _)| name.starts_with(word))
+                .map(|(name, _)| Pair {
+                    display: name.to_string(),
+                    replacement: format!("{} ", name),
+                })
+                .collect();
+        }
+
+        let (dir, prefix) = match word.rfind('/') {
+            Some(i) => word.split_at(i + 1),
+            None => ("", word),
+        };
+        let listed = resolve(&self.cwd, if dir.is_empty() { "." } else { dir });
+        let mut names = self.client.list_dir(&listed).unwrap_or_default();
+        names.retain(|name| name.starts_with(prefix));
+        names.sort();
+
+        let paths: Vec<String> = names
+            .iter()
+            .map(|name| commands::join(&listed, name))
+            .collect();
+        let infos = self.client.stat_many(&paths);
+        names
+            .into_iter()
+            .zip(infos)
+            .map(|(name, info)| {
+                let name = if info.is_ok_and(|info| info.is_dir()) {
+                    format!("{}/", name)
+                } else {
+                    name
+                };
+                Pair {
+                    replacement: format!("{}{}", dir, name),
+                    display: name,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Completer for Shell {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(' ').map_or(0, |i| i + 1);
+        let command = line[..start].trim().is_empty();
+        Ok((start, self.candidates(&line[start..pos], command)))
+    }
+}
+
+impl Hinter for Shell {
+    type Hint = String;
+}
+
+impl Highlighter for Shell {}
+
+impl Validator for Shell {}
+
+impl Helper for Shell {}
+
+/// Directory a session starts in, the documents in house_arrest mode
+fn root(app_id: Option<&str>) -> &'static str {
+    match app_id {
+        Some(_) => "/Documents",
+        None => "/",
+    }
+}
+
+/// Absolute path of `path` typed in `cwd`, with `.` and `..` resolved
+fn resolve(cwd: &str, path: &str) -> String {
+    let joined = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("{}/{}", cwd, path)
+    };
+    let mut parts = Vec::new();
+    for part in joined.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+/// Words of a command line, where quotes keep spaces in a word
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+/// Flags given as `-x` anywhere on the line, and the other arguments
+fn options(args: &[String]) -> (String, Vec<&str>) {
+    let mut flags = String::new();
+    let mut rest = Vec::new();
+    for arg in args {
+        match arg.strip_prefix('-') {
+            Some(arg) if !arg.is_empty() => flags.push_str(arg),
+            _ => rest.push(arg.as_str()),
+        }
+    }
+    (flags, rest)
+}
+
+/// Size in bytes with a binary unit, as `df -h` prints it
+fn human(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "K", "M", "G"] {
+        if size < 1024.0 {
+            return match unit {
+                "B" => format!("{}{}", bytes, unit),
+                _ => format!("{:.1}{}", size, unit),
+            };
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1}T", size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+
+    #[test]
+    fn paths_are_resolved_against_the_working_directory() {
+        assert_eq!(resolve("/", "DCIM"), "/DCIM");
+        assert_eq!(
+            resolve("/DCIM/100APPLE", "../101APPLE/./a"),
+            "/DCIM/101APPLE/a"
+        );
+        assert_eq!(resolve("/DCIM", "/Downloads/"), "/Downloads");
+        assert_eq!(resolve("/DCIM", "../.."), "/");
+    }
+
+    #[test]
+    fn lines_are_split_into_words_and_flags() {
+        let words = split_words(r#"get -r "My Photos" 'a b'  ''"#);
+        assert_eq!(words, ["get", "-r", "My Photos", "a b", ""]);
+        let (flags, args) = options(&words[1..]);
+        assert_eq!(flags, "r");
+        assert_eq!(args, ["My Photos", "a b", ""]);
+        assert_eq!(human(512), "512B");
+        assert_eq!(human(64 << 30), "64.0G");
+    }
+
+    #[test]
+    fn completes_commands_and_remote_paths() {
+        crate::VERBOSE.get_or_init(|| false);
+        let (server, client) = MockServer::start();
+        std::fs::create_dir_all(server.root().join("DCIM/100APPLE")).unwrap();
+        std::fs::write(server.root().join("DCIM/101.txt"), b"").unwrap();
+        std::fs::write(server.root().join("Downloads"), b"").unwrap();
+        let shell = Shell {
+            device: std::ptr::null_mut(),
+            client,
+            app_id: None,
+            cwd: "/".to_string(),
+        };
+
+        let names = |pairs: Vec<Pair>| -> Vec<String> {
+            pairs.into_iter().map(|pair| pair.replacement).collect()
+        };
+        assert_eq!(names(shell.candidates("m", true)), ["mkdir ", "mv "]);
+        assert_eq!(names(shell.candidates("D", false)), ["DCIM/", "Downloads"]);
+        assert_eq!(
+            names(shell.candidates("DCIM/10", false)),
+            ["DCIM/100APPLE/", "DCIM/101.txt"]
+        );
+        assert!(shell.candidates("missing/", false).is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+#![allow(dead_code)]
+use crate::{
+    idevice_connection_receive_timeout, idevice_connection_send, idevice_connection_t,
+    idevice_disconnect, idevice_error_t, idevice_error_t_IDEVICE_E_SUCCESS,
+    idevice_error_t_IDEVICE_E_TIMEOUT, idevice_error_t_IDEVICE_E_UNKNOWN_ERROR,
+};
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+/// Byte stream the AFC, house_arrest and instproxy protocols are spoken over.
+///
+/// Results follow `idevice_connection_send`/`idevice_connection_receive_timeout`
+/// so every implementation reports errors as `idevice_error_t`.
+pub trait Transport: Send {
+    /// Sends `data`, returning the status and the number of bytes sent
+    fn send(&mut self, data: &[u8]) -> (idevice_error_t, u32);
+
+    /// Fills `buf`, waiting at most `timeout` milliseconds (0 waits forever)
+    fn receive_timeout(&mut self, buf: &mut [u8], timeout: u32) -> (idevice_error_t, u32);
+
+    fn disconnect(&mut self) -> idevice_error_t;
+}
+
+/// libimobiledevice connection to a lockdown service on the device
+pub(crate) struct IDeviceConnection {
+    pub(crate) conn: usize,
+}
+
+impl IDeviceConnection {
+    pub(crate) fn new(connection: idevice_connection_t) -> Self {
+        Self {
+            conn: connection as usize,
+        }
+    }
+
+    pub(crate) fn none() -> Self {
+        Self { conn: 0 }
+    }
+
+    pub(crate) fn connection(&self) -> idevice_connection_t {
+        self.conn as idevice_connection_t
+    }
+}
+
+impl Transport for IDeviceConnection {
+    fn send(&mut self, data: &[u8]) -> (idevice_error_t, u32) {
+        let mut sent_bytes = 0;
+        let res = unsafe {
+            idevice_connection_send(
+                self.connection(),
+                data.as_ptr() as *const i8,
+                data.len() as u32,
+                &mut sent_bytes,
+            )
+        };
+        (res, sent_bytes)
+    }
+
+    fn receive_timeout(&mut self, buf: &mut [u8], timeout: u32) -> (idevice_error_t, u32) {
+        let mut recv_bytes = 0;
+        let res = unsafe {
+            idevice_connection_receive_timeout(
+                self.connection(),
+                buf.as_mut_ptr() as *mut i8,
+                buf.len() as u32,
+                &mut recv_bytes,
+                timeout,
+            )
+        };
+        (res, recv_bytes)
+    }
+
+    fn disconnect(&mut self) -> idevice_error_t {
+        if self.conn == 0 {
+            return idevice_error_t_IDEVICE_E_SUCCESS;
+        }
+        let res = unsafe { idevice_disconnect(self.connection()) };
+        self.conn = 0;
+        res
+    }
+}
+
+/// Plain TCP connection, e.g. to a local stand-in for the device service
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> std::io::Result<Self> {
+        Ok(Self::from(TcpStream::connect(addr)?))
+    }
+}
+
+impl From<TcpStream> for TcpTransport {
+    fn from(stream: TcpStream) -> Self {
+        let _ = stream.set_nodelay(true);
+        Self { stream }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&mut self, data: &[u8]) -> (idevice_error_t, u32) {
+        match self.stream.write_all(data) {
+            Ok(_) => (idevice_error_t_IDEVICE_E_SUCCESS, data.len() as u32),
+            Err(_) => (idevice_error_t_IDEVICE_E_UNKNOWN_ERROR, 0),
+        }
+    }
+
+    fn receive_timeout(&mut self, buf: &mut [u8], timeout: u32) -> (idevice_error_t, u32) {
+        let deadline = deadline(timeout);
+        let mut received = 0;
+        while received < buf.len() {
+            let wait = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(wait) if !wait.is_zero() => Some(wait),
+                    _ => return (idevice_error_t_IDEVICE_E_TIMEOUT, received as u32),
+                },
+                None => None,
+            };
+            if self.stream.set_read_timeout(wait).is_err() {
+                return (idevice_error_t_IDEVICE_E_UNKNOWN_ERROR, received as u32);
+            }
+
+            match self.stream.read(&mut buf[received..]) {
+                Ok(0) => return (idevice_error_t_IDEVICE_E_UNKNOWN_ERROR, received as u32),
+                Ok(n) => received += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return (idevice_error_t_IDEVICE_E_TIMEOUT, received as u32)
+                }
+                Err(_) => return (idevice_error_t_IDEVICE_E_UNKNOWN_ERROR, received as u32),
+            }
+        }
+        (idevice_error_t_IDEVICE_E_SUCCESS, received as u32)
+    }
+
+    fn disconnect(&mut self) -> idevice_error_t {
+        let _ = self.stream.shutdown(Shutdown::Both);
+        idevice_error_t_IDEVICE_E_SUCCESS
+    }
+}
+
+/// One end of an in-process duplex pipe
+pub struct MemoryTransport {
+    tx: Option<Sender<Vec<u8>>>,
+    rx: Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl MemoryTransport {
+    /// Creates two connected ends; what one sends the other receives
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, a_rx) = channel();
+        let (b_tx, b_rx) = channel();
+        (
+            Self {
+                tx: Some(a_tx),
+                rx: b_rx,
+                pending: Vec::new(),
+            },
+            Self {
+                tx: Some(b_tx),
+                rx: a_rx,
+                pending: Vec::new(),
+            },
+        )
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send(&mut self, data: &[u8]) -> (idevice_error_t, u32) {
+        match &self.tx {
+            Some(tx) if tx.send(data.to_vec()).is_ok() => {
+                (idevice_error_t_IDEVICE_E_SUCCESS, data.len() as u32)
+            }
+            _ => (idevice_error_t_IDEVICE_E_UNKNOWN_ERROR, 0),
+        }
+    }
+
+    fn receive_timeout(&mut self, buf: &mut [u8], timeout: u32) -> (idevice_error_t, u32) {
+        let deadline = deadline(timeout);
+        let mut received = 0;
+        while received < buf.len() {
+            if self.pending.is_empty() {
+                let chunk = match deadline {
+                    Some(deadline) => {
+                        let wait = deadline.saturating_duration_since(Instant::now());
+                        self.rx.recv_timeout(wait)
+                    }
+                    None => self.rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match chunk {
+                    Ok(chunk) => self.pending = chunk,
+                    Err(RecvTimeoutError::Timeout) => {
+                        return (idevice_error_t_IDEVICE_E_TIMEOUT, received as u32)
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        return (idevice_error_t_IDEVICE_E_UNKNOWN_ERROR, received as u32)
+                    }
+                }
+            }
+
+            let len = self.pending.len().min(buf.len() - received);
+            buf[received..received + len].copy_from_slice(&self.pending[..len]);
+            self.pending.drain(..len);
+            received += len;
+        }
+        (idevice_error_t_IDEVICE_E_SUCCESS, received as u32)
+    }
+
+    fn disconnect(&mut self) -> idevice_error_t {
+        // stop receiving before the peer can notice, so its next send fails too
+        self.rx = channel().1;
+        self.pending.clear();
+        self.tx = None;
+        idevice_error_t_IDEVICE_E_SUCCESS
+    }
+}
+
+fn deadline(timeout: u32) -> Option<Instant> {
+    if timeout == 0 {
+        None
+    } else {
+        Some(Instant::now() + Duration::from_millis(timeout as u64))
+    }
+}
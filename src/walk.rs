@@ -0,0 +1,353 @@
+use crate::{
+    afc::{AfcError, Client},
+    fs::{DirEntry, FileInfo},
+};
+use glob::{MatchOptions, Pattern};
+use std::{
+    path::{Component, Path, PathBuf},
+    vec,
+};
+
+/// Links followed in a row before a symlink is taken as broken
+const MAX_LINK_HOPS: usize = 8;
+
+/// What a `Walk` does with symbolic links
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Leaves them out
+    Skip,
+    /// Yields them as links, without going into them
+    #[default]
+    Yield,
+    /// Yields them with the attributes of what they point to, and goes into those that
+    /// lead to a directory. Broken links and links back up the tree are yielded as links.
+    Follow,
+}
+
+/// A directory a `Walk` could not list, such as a system one the device refuses
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalkError {
+    pub path: PathBuf,
+    pub status: AfcError,
+}
+
+impl std::fmt::Display for WalkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.status)
+    }
+}
+
+impl std::error::Error for WalkError {}
+
+/// Depth-first walk below a directory, made with `Client::walk`.
+///
+/// Directories are listed with `Client::read_dir` as the walk reaches them, and their
+/// entries come in name order, each directory right before what is in it. A directory
+/// that cannot be listed is yielded as an error and the walk goes on past it.
+pub struct Walk<'a> {
+    client: &'a Client,
+    root: PathBuf,
+    max_depth: usize,
+    patterns: Vec<(Pattern, bool)>,
+    symlinks: Symlinks,
+    /// Directories being walked, innermost last
+    levels: Vec<Level>,
+    /// Directory to list before going on
+    pending: Option<Directory>,
+}
+
+struct Directory {
+    /// Where the walk has it, below the link it was reached through if any
+    path: PathBuf,
+    /// Where it is on the device
+    real: PathBuf,
+    depth: usize,
+}
+
+struct Level {
+    directory: Directory,
+    entries: vec::IntoIter<DirEntry>,
+}
+
+impl Client {
+    /// Walks everything below the directory `path`, see `Walk`
+    pub fn walk(&self, path: impl AsRef<Path>) -> Walk<'_> {
+        let root = path.as_ref().to_path_buf();
+        Walk {
+            client: self,
+            pending: Some(Directory {
+                path: root.clone(),
+                real: normalize(&root),
+                depth: 0,
+            }),
+            root,
+            max_depth: usize::MAX,
+            patterns: Vec::new(),
+            symlinks: Symlinks::default(),
+            levels: Vec::new(),
+        }
+    }
+}
+
+impl Walk<'_> {
+    /// Goes no deeper than `depth` levels, 1 being the entries of the directory walked
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        if depth == 0 {
+            self.pending = None;
+        }
+        self
+    }
+
+    /// Only yields entries matching `pattern`, or any of the patterns given. A pattern
+    /// with a `/` is matched against the path below the directory walked, one without
+    /// against the name. Directories are still walked into when they do not match.
+    pub fn glob(mut self, pattern: &str) -> Result<Self, AfcError> {
+        let compiled = Pattern::new(pattern).map_err(|_| AfcError::InvalidArg)?;
+        self.patterns.push((compiled, pattern.contains('/')));
+        Ok(self)
+    }
+
+    /// What to do with symbolic links, `Symlinks::Yield` unless set
+    pub fn symlinks(mut self, symlinks: Symlinks) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    fn matches(&self, entry: &DirEntry) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        self.patterns.is_empty()
+            || self.patterns.iter().any(|(pattern, nested)| {
+                if *nested {
+                    let relative = entry.path.strip_prefix(&self.root).unwrap_or(&entry.path);
+                    pattern.matches_path_with(relative, options)
+                } else {
+                    pattern.matches_with(&entry.name, options)
+                }
+            })
+    }
+
+    /// What the symlink at `link` finally points to, `None` if it does not lead
+    /// anywhere
+    fn resolve(&self, link: &Path, info: &FileInfo) -> Option<(PathBuf, FileInfo)> {
+        let mut link = link.to_path_buf();
+        let mut info = info.clone();
+        for _ in 0..MAX_LINK_HOPS {
+            link = normalize(&link.parent()?.join(info.link_target.as_deref()?));
+            info = self.client.stat(&link).ok()?;
+            if !info.is_symlink() {
+                return Some((link, info));
+            }
+        }
+        None
+    }
+
+    /// Whether `real` is a directory being walked or one above it
+    fn is_ancestor(&self, real: &Path) -> bool {
+        self.levels
+            .iter()
+            .any(|level| level.directory.real.starts_with(real))
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = Result<DirEntry, WalkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(directory) = self.pending.take() {
+                match self.client.read_dir(&directory.real) {
+                    Ok(entries) => self.levels.push(Level {
+                        directory,
+                        entries: entries.into_iter(),
+                    }),
+                    Err(status) => {
+                        return Some(Err(WalkError {
+                            path: directory.path,
+                            status,
+                        }))
+                    }
+                }
+            }
+
+            let level = self.levels.last_mut()?;
+            let Some(mut entry) = level.entries.next() else {
+                self.levels.pop();
+                continue;
+            };
+            let depth = level.directory.depth + 1;
+            let mut real = level.directory.real.join(&entry.name);
+            entry.path = level.directory.path.join(&entry.name);
+
+            if entry.info.is_symlink() {
+                match self.symlinks {
+                    Symlinks::Skip => continue,
+                    Symlinks::Yield => {}
+                    Symlinks::Follow => {
+                        if let Some((target, info)) = self.resolve(&real, &entry.info) {
+                            if !(info.is_dir() && self.is_ancestor(&target)) {
+                                real = target;
+                                entry.info = info;
+                            }
+                        }
+                    }
+                }
+            }
+
+            if entry.info.is_dir() && depth < self.max_depth {
+                self.pending = Some(Directory {
+                    path: entry.path.clone(),
+                    real,
+                    depth,
+                });
+            }
+            if self.matches(&entry) {
+                return Some(Ok(entry));
+            }
+        }
+    }
+}
+
+/// `path` with `.` and `..` worked out, as the device would
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+
+    fn paths(walk: Walk) -> Vec<String> {
+        walk.map(|entry| entry.unwrap().path.to_string_lossy().to_string())
+            .collect()
+    }
+
+    fn start() -> (MockServer, Client) {
+        crate::VERBOSE.get_or_init(|| false);
+        let (server, client) = MockServer::start();
+        client.create_dir("/DCIM/100APPLE").unwrap();
+        client.create_dir("/DCIM/101APPLE").unwrap();
+        for file in [
+            "DCIM/100APPLE/IMG_1.JPG",
+            "DCIM/101APPLE/IMG_2.HEIC",
+            "DCIM/notes.txt",
+        ] {
+            std::fs::write(server.root().join(file), b"data").unwrap();
+        }
+        (server, client)
+    }
+
+    #[test]
+    fn walks_depth_first_in_name_order() {
+        let (_server, client) = start();
+        assert_eq!(
+            paths(client.walk("/DCIM")),
+            [
+                "/DCIM/100APPLE",
+                "/DCIM/100APPLE/IMG_1.JPG",
+                "/DCIM/101APPLE",
+                "/DCIM/101APPLE/IMG_2.HEIC",
+                "/DCIM/notes.txt",
+            ]
+        );
+        assert_eq!(
+            paths(client.walk("/").max_depth(2)),
+            [
+                "/DCIM",
+                "/DCIM/100APPLE",
+                "/DCIM/101APPLE",
+                "/DCIM/notes.txt"
+            ]
+        );
+        assert!(paths(client.walk("/").max_depth(0)).is_empty());
+
+        let files: Vec<_> = client.walk("/").filter_map(Result::ok).collect();
+        let image = files
+            .iter()
+            .find(|entry| entry.name == "IMG_1.JPG")
+            .unwrap();
+        assert!(image.info.is_file());
+        assert_eq!(image.info.size, 4);
+    }
+
+    #[test]
+    fn globs_match_names_or_paths() {
+        let (_server, client) = start();
+        assert_eq!(
+            paths(
+                client
+                    .walk("/")
+                    .glob("*.JPG")
+                    .unwrap()
+                    .glob("*.HEIC")
+                    .unwrap()
+            ),
+            ["/DCIM/100APPLE/IMG_1.JPG", "/DCIM/101APPLE/IMG_2.HEIC"]
+        );
+        assert_eq!(
+            paths(client.walk("/").glob("DCIM/*").unwrap()),
+            ["/DCIM/100APPLE", "/DCIM/101APPLE", "/DCIM/notes.txt"]
+        );
+        assert!(client.walk("/").glob("[").is_err());
+    }
+
+    #[test]
+    fn refused_directories_are_reported_and_passed() {
+        let (server, client) = start();
+        server.deny("/DCIM/100APPLE");
+        let results: Vec<_> = client.walk("/DCIM").collect();
+        assert_eq!(results.len(), 5);
+        assert_eq!(
+            results[1],
+            Err(WalkError {
+                path: PathBuf::from("/DCIM/100APPLE"),
+                status: AfcError::PermDenied
+            })
+        );
+        assert_eq!(results[4].as_ref().unwrap().name, "notes.txt");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlinks_follow_the_policy() {
+        use std::os::unix::fs::symlink;
+        let (server, client) = start();
+        symlink("../DCIM/101APPLE", server.root().join("DCIM/latest")).unwrap();
+        symlink("..", server.root().join("DCIM/100APPLE/up")).unwrap();
+        symlink("missing", server.root().join("DCIM/broken")).unwrap();
+
+        let all = paths(client.walk("/DCIM"));
+        assert!(all.contains(&"/DCIM/latest".to_string()));
+        assert!(!all.contains(&"/DCIM/latest/IMG_2.HEIC".to_string()));
+
+        let skipped = paths(client.walk("/DCIM").symlinks(Symlinks::Skip));
+        assert!(!skipped.iter().any(|path| path.ends_with("latest")));
+
+        let followed: Vec<_> = client
+            .walk("/DCIM")
+            .symlinks(Symlinks::Follow)
+            .map(Result::unwrap)
+            .collect();
+        let find = |path: &str| followed.iter().find(|entry| entry.path == Path::new(path));
+        assert!(find("/DCIM/latest").unwrap().info.is_dir());
+        assert!(find("/DCIM/latest/IMG_2.HEIC").unwrap().info.is_file());
+        assert!(find("/DCIM/broken").unwrap().info.is_symlink());
+        // leads back up, so it is not gone into
+        assert!(find("/DCIM/100APPLE/up").unwrap().info.is_symlink());
+        assert_eq!(followed.len(), 9);
+    }
+}
@@ -0,0 +1,246 @@
+#![allow(non_upper_case_globals)]
+use crate::{
+    debug, device, fuse_unmount, idevice_connection_type_CONNECTION_NETWORK,
+    idevice_connection_type_CONNECTION_USBMUXD, idevice_error_t_IDEVICE_E_SUCCESS, idevice_event_t,
+    idevice_event_type_IDEVICE_DEVICE_ADD, idevice_event_type_IDEVICE_DEVICE_PAIRED,
+    idevice_event_type_IDEVICE_DEVICE_REMOVE, idevice_events_subscribe, idevice_events_unsubscribe,
+    idevice_options, idevice_options_IDEVICE_LOOKUP_NETWORK, idevice_options_IDEVICE_LOOKUP_USBMUX,
+};
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    os::raw::c_void,
+    path::Path,
+    process::{Child, Command},
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+/// Time a mount gets to exit after being unmounted before it is killed
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Event {
+    Added(String, idevice_options),
+    Removed(String, idevice_options),
+    Stop,
+}
+
+/// A device mounted by a child process running the usual single mount
+struct Mount {
+    mount_point: String,
+    lookup: idevice_options,
+    child: Child,
+}
+
+/// Mounts every device that shows up at `template` until Ctrl+C, unmounting it again
+/// when it goes away. `mount_args` are passed on to each mount.
+pub(crate) fn run(
+    template: &str,
+    udid: Option<&str>,
+    options: idevice_options,
+    mount_args: Vec<String>,
+) {
+    let (tx, rx) = channel();
+    let stop = tx.clone();
+    ctrlc::set_handler(move || {
+        let _ = stop.send(Event::Stop);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    let sender = Box::into_raw(Box::new(tx));
+    let mut context = std::ptr::null_mut();
+    let res = unsafe { idevice_events_subscribe(&mut context, Some(on_event), sender as _) };
+    if res != idevice_error_t_IDEVICE_E_SUCCESS {
+        eprintln!("Cannot watch for devices:{:?}", res);
+        drop(unsafe { Box::from_raw(sender) });
+        return;
+    }
+    println!("Waiting for devices...");
+
+    let mut mounts: HashMap<String, Mount> = HashMap::new();
+    loop {
+        let event = match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        // mounts that ended by themselves, e.g. because the device was not trusted yet
+        mounts.retain(|udid, mount| match mount.child.try_wait() {
+            Ok(Some(status)) => {
+                println!(
+                    "Mount of {} at {} ended: {}",
+                    udid, mount.mount_point, status
+                );
+                false
+            }
+            _ => true,
+        });
+
+        match event {
+            Some(Event::Added(found, lookup)) => {
+                if options & lookup == 0
+                    || udid.is_some_and(|udid| udid != found)
+                    || mounts.contains_key(&found)
+                {
+                    continue;
+                }
+                if let Some(mount) = mount(template, &found, lookup, &mount_args, &mounts) {
+                    mounts.insert(found, mount);
+                }
+            }
+            // the device may still be reachable over its other connection
+            Some(Event::Removed(found, lookup))
+                if mounts
+                    .get(&found)
+                    .is_some_and(|mount| mount.lookup == lookup) =>
+            {
+                unmount(mounts.remove(&found).unwrap());
+            }
+            Some(Event::Stop) => break,
+            _ => {}
+        }
+    }
+
+    unsafe { idevice_events_unsubscribe(context) };
+    drop(unsafe { Box::from_raw(sender) });
+    for (_, mount) in mounts.drain() {
+        unmount(mount);
+    }
+}
+
+unsafe extern "C" fn on_event(event: *const idevice_event_t, user_data: *mut c_void) {
+    let event = &*event;
+    let sender = &*(user_data as *const Sender<Event>);
+    let lookup = match event.conn_type {
+        idevice_connection_type_CONNECTION_USBMUXD => idevice_options_IDEVICE_LOOKUP_USBMUX,
+        idevice_connection_type_CONNECTION_NETWORK => idevice_options_IDEVICE_LOOKUP_NETWORK,
+        _ => return,
+    };
+    let udid = CStr::from_ptr(event.udid).to_string_lossy().to_string();
+    debug!("Device event {} for {}", event.event, udid);
+
+    // a device that was waiting for the trust dialog can be mounted once paired
+    let _ = match event.event {
+        idevice_event_type_IDEVICE_DEVICE_ADD | idevice_event_type_IDEVICE_DEVICE_PAIRED => {
+            sender.send(Event::Added(udid, lookup))
+        }
+        idevice_event_type_IDEVICE_DEVICE_REMOVE => sender.send(Event::Removed(udid, lookup)),
+        _ => Ok(()),
+    };
+}
+
+fn mount(
+    template: &str,
+    udid: &str,
+    lookup: idevice_options,
+    mount_args: &[String],
+    mounts: &HashMap<String, Mount>,
+) -> Option<Mount> {
+    let name = device::device_name(&CString::new(udid).ok()?, lookup).unwrap_or_default();
+    let mount_point = mount_point(template, udid, &name);
+    if mounts
+        .values()
+        .any(|mount| mount.mount_point == mount_point)
+    {
+        eprintln!("{} is in use, not mounting {}", mount_point, udid);
+        return None;
+    }
+    // a drive letter is created by the mount, a directory has to exist
+    if !is_drive(&mount_point) && !Path::new(&mount_point).exists() {
+        if let Err(e) = std::fs::create_dir_all(&mount_point) {
+            eprintln!("Cannot create {}: {}", mount_point, e);
+            return None;
+        }
+    }
+
+    let transport = if lookup == idevice_options_IDEVICE_LOOKUP_NETWORK {
+        "--network"
+    } else {
+        "--usb"
+    };
+    let exe = std::env::current_exe().ok()?;
+    let child = Command::new(exe)
+        .arg(&mount_point)
+        .args(["-u", udid, transport])
+        .args(mount_args)
+        .spawn();
+    match child {
+        Ok(child) => {
+            println!("Mounting {} ({}) at {}", udid, name, mount_point);
+            Some(Mount {
+                mount_point,
+                lookup,
+                child,
+            })
+        }
+        Err(e) => {
+            eprintln!("Cannot start mount of {}: {}", udid, e);
+            None
+        }
+    }
+}
+
+fn unmount(mut mount: Mount) {
+    println!("Unmounting...:{:?}", mount.mount_point);
+    let c = CString::new(mount.mount_point.clone()).unwrap();
+    unsafe { fuse_unmount(c.as_ptr(), std::ptr::null_mut()) };
+
+    let deadline = Instant::now() + EXIT_TIMEOUT;
+    while Instant::now() < deadline {
+        if let Ok(Some(_)) = mount.child.try_wait() {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    eprintln!("Mount at {} did not exit, killing it", mount.mount_point);
+    let _ = mount.child.kill();
+    let _ = mount.child.wait();
+}
+
+/// Mount point for a device, with `{udid}` and `{name}` in the template filled in
+fn mount_point(template: &str, udid: &str, name: &str) -> String {
+    // device names are free text, keep them to one path component
+    let name: String = name
+        .trim()
+        .chars()
+        .map(|c| {
+            if "\\/:*?\"<>|".contains(c) || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    let name = if name.is_empty() { udid } else { &name };
+    template.replace("{udid}", udid).replace("{name}", name)
+}
+
+fn is_drive(mount_point: &str) -> bool {
+    let bytes = mount_point.trim_end_matches(['\\', '/']).as_bytes();
+    bytes.len() == 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_is_filled_with_a_safe_name() {
+        assert_eq!(
+            mount_point("C:\\iphone\\{name}", "0001", "Kiosk: \"A\"/B"),
+            "C:\\iphone\\Kiosk_ _A__B"
+        );
+        assert_eq!(mount_point("/mnt/{udid}", "0001", "x"), "/mnt/0001");
+        assert_eq!(mount_point("/mnt/{name}", "0001", " "), "/mnt/0001");
+        assert_eq!(mount_point("X:", "0001", "x"), "X:");
+    }
+
+    #[test]
+    fn drive_letters_are_recognised() {
+        assert!(is_drive("X:"));
+        assert!(is_drive("x:\\"));
+        assert!(!is_drive("C:\\iphone"));
+        assert!(!is_drive("/mnt/x"));
+    }
+}